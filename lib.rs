@@ -2,6 +2,7 @@
 #![crate_type = "lib"]
 #![desc = "Library to parse simple command line options"]
 #![license = "MIT"]
+#![feature(macro_rules)]
 
 /*!
   Command line option parsing
@@ -12,13 +13,30 @@
   - Grouping of short options
   - Automatic help message generation.
   - Commands taking their own options
+  - Validation of option values with custom validators
+  - Optional abbreviation of long options
+  - Aliases for options and commands
+  - Negatable flags (--foo/--no-foo)
+  - Bounded counters for verbosity levels (-vvv, -q, --verbose=2)
+  - Typed options, with values checked during validation
+  - Declaration of a struct of options with cmdparse_options!
+  - Options taking several values at once (--point 1 2)
+  - Lists of values split at a delimiter (--tags=a,b,c)
+  - Maps of key=value entries (-Dname=value)
+  - Optional expansion of response files (@file)
+  - Parsing of whole command lines with shell-like quoting
+  - Values which aren't valid UTF-8, eg file names
+  - Owned copies of the results, which can be sent to other tasks
+  - Export of the results as JSON, eg for audit logs
+  - Automatic -V/--version option
+  - Optional automatic -h/--help options and help command
 
   # Example, to parse the options :
   "-h/--help, -l, --option, -a [optional_argument(int)], -m mandatory_argument(str) leftover_argument"
 
   ```rust
   // First create the context with the program summary and the input arguments :
-  let mut ctx = Context::new("cmdparse [options]", os::args());
+  let mut ctx = Context::from_env("cmdparse [options]");
 
   // Then add the authorized options.
   // Use the convenience wrappers :
@@ -51,11 +69,10 @@
     return;
   }
 
-  match a_opt.take_value::<int>() {
-    Ok(Some(some_int)) => println!("a : {:d}", some_int),
-    Ok(None) => println!("a : the argument should be an int!!"),
-    Err(passed) => if passed {
-      println!("the option 'a' was passed without an argument.");
+  match a_opt.value::<int>() {
+    Some(some_int) => println!("a : {:d}", some_int),
+    None => if a_opt.check() {
+      println!("the option 'a' was passed without a valid argument.");
     } else {
       println!("the option 'a' was not passed.");
     }
@@ -68,20 +85,70 @@
   // etc.
   ```
 
+  # Example with the automatic help and version
+
+  With enable_auto_help() or set_version(), use validate_outcome() instead
+  of validate(): it tells apart a help request, and the command it is
+  about, from an invalid input. validate() reports a help request as an
+  Err() like an invalid input.
+
+  ```rust
+  let mut ctx = Context::from_env("cmdparse [options] command");
+  ctx.add_cmd_with("deploy", "Deploy the application", |cmd| {
+    cmd.add_option(Some("target"), None, Some("Where to deploy"), Flags::TakesArg).unwrap();
+  });
+  ctx.set_version("cmdparse 1.0").unwrap();
+  // After the options and commands using -h, --help or help, if any
+  ctx.enable_auto_help();
+
+  match ctx.validate_outcome() {
+    Err(msg) => {
+      ctx.print_help(Some(msg.as_slice()));
+      return;
+    }
+    Ok(HelpRequested(None)) => {
+      ctx.print_help(None);
+      return;
+    }
+    Ok(HelpRequested(Some(command))) => {
+      ctx.print_command_help(command.as_slice(), None).unwrap();
+      return;
+    }
+    Ok(VersionPrinted) => return,
+    Ok(Proceed) => {}
+  }
+  ```
+
 */
 
 extern crate collections;
+extern crate serialize;
 
+use collections::TreeMap;
 use collections::hashmap::HashMap;
-use std::cast::transmute;
+use serialize::json::{Json, ToJson};
+use serialize::json;
+use std::any::{Any, AnyRefExt};
 use std::cell::RefCell;
 use std::fmt::{Show, Formatter};
 use std::fmt;
 use std::from_str::FromStr;
+use std::io::File;
+use std::io;
+use std::kinds::marker;
+use std::os;
 use std::result::Result;
 use std::rc::Rc;
+use std::str::{IntoMaybeOwned, MaybeOwned};
+use std::str;
+use std::strbuf::StrBuf;
+use std::sync::atomics::{AtomicUint, INIT_ATOMIC_UINT, SeqCst};
+use std::uint;
+use std::vec::MoveItems;
 
 static min_align: uint = 15;
+// Source of the option ids, unique in the whole program.
+static mut next_opt_id: AtomicUint = INIT_ATOMIC_UINT;
 
 pub mod Flags {
   pub static Defaults: uint = 0;
@@ -89,8 +156,204 @@ pub mod Flags {
   pub static Hidden: uint = 1 << 1;
   pub static TakesArg: uint = 1 << 2;
   pub static TakesOptionalArg: uint = 1 << 3;
+  /// Also accept --no-<long name> to turn the option off. Requires a long name.
+  pub static Negatable: uint = 1 << 4;
+  /// Replace the values of the option in the JSON export of the results.
+  pub static Secret: uint = 1 << 5;
+}
+
+pub mod CtxFlags {
+  pub static Defaults: uint = 0;
+  /// Accept unambiguous prefixes of long option names, eg --verb for --verbose.
+  pub static AbbreviatedOptions: uint = 1 << 0;
+  /// Replace the arguments of the form @file by the arguments in the file.
+  /// They are split at whitespaces, with shell-like quoting. The nested
+  /// response files are relative to the file including them.
+  pub static ResponseFiles: uint = 1 << 1;
+  /// When no command is given, reject a first argument close to a command
+  /// name, suggesting that command, eg "Did you mean deploy?" for deplyo.
+  pub static RejectCommandTypos: uint = 1 << 2;
+}
+
+/// What the program should do after Context::validate_outcome().
+#[deriving(Clone, Eq, Show)]
+pub enum Outcome {
+  /// The arguments are valid, go on.
+  Proceed,
+  /// The help was requested for the given command, or for the whole
+  /// program if None. The arguments after the help option weren't checked.
+  /// See Context::enable_auto_help().
+  HelpRequested(Option<~str>),
+  /// The version was printed, see Context::set_version(). The arguments
+  /// after the version option weren't checked.
+  VersionPrinted,
+}
+
+/// Number of values an option takes each time it is given. See Opt::set_arity().
+#[deriving(Clone, Eq, Show)]
+pub enum Arity {
+  /// Exactly this number of values.
+  Exactly(uint),
+  /// Between min and max values (inclusive).
+  Between(uint, uint),
+  /// At least this number of values, taking every value until the next option.
+  AtLeast(uint),
+}
+
+/// What to do when a key is given several times to a map option.
+#[deriving(Clone, Eq, Show)]
+pub enum DuplicateKeys {
+  /// Context::validate() returns Err().
+  RejectDuplicates,
+  /// The last value given for the key is kept.
+  LastWins,
+}
+
+/// A check run on each value given to an option, during Context::validate().
+pub trait Validator {
+  /// Return Err() with a message explaining why the value was rejected.
+  fn check(&self, value: &str) -> Result<(), ~str>;
+}
+
+impl Validator for fn(&str) -> Result<(), ~str> {
+  fn check(&self, value: &str) -> Result<(), ~str> {
+    (*self)(value)
+  }
+}
+
+/// Validator accepting values which can be parsed into a T
+/// between min and max (inclusive).
+pub struct InRange<T> {
+  pub min: T,
+  pub max: T,
+}
+
+impl<T: FromStr + Ord + Show + Send> Validator for InRange<T> {
+  fn check(&self, value: &str) -> Result<(), ~str> {
+    match from_str::<T>(value) {
+      Some(ref tvalue) if *tvalue >= self.min && *tvalue <= self.max => Ok(()),
+      _ => Err(format!("expected a value between {} and {}", self.min, self.max)),
+    }
+  }
+}
+
+// Conversion used by typed options. The result is kept in the Res of the
+// option, so that TypedOpt doesn't have to parse the value again.
+fn parse_any<T: FromStr + Send>(value: &str) -> Option<~Any> {
+  from_str::<T>(value).map(|value| ~value as ~Any)
+}
+
+// Wrapper giving a Show implementation to the conversion of a typed option.
+struct Parser(fn(&str) -> Option<~Any>);
+
+// Validator used by the fields of cmdparse_options! holding values.
+fn parses<T: FromStr>(value: &str) -> Result<(), ~str> {
+  match from_str::<T>(value) {
+    Some(_) => Ok(()),
+    None => Err(~"the value doesn't have the expected type"),
+  }
+}
+
+/// Conversion of the results of an option into a field of a struct
+/// declared with cmdparse_options!.
+pub trait FromOpt {
+  fn from_opt(opt: &Opt) -> Self;
+
+  /// Validator added to the option by register(), so that values which
+  /// can't be converted are reported by validate(). The argument is only
+  /// used to select the implementation.
+  fn validator(_: Option<Self>) -> Option<fn(&str) -> Result<(), ~str>> {
+    None
+  }
+}
+
+/// Whether the option was given.
+impl FromOpt for bool {
+  fn from_opt(opt: &Opt) -> bool {
+    opt.check()
+  }
 }
 
+/// Number of times the option was given.
+impl FromOpt for uint {
+  fn from_opt(opt: &Opt) -> uint {
+    opt.count()
+  }
+}
+
+/// Last value given to the option, None if there is none.
+impl<T: FromStr> FromOpt for Option<T> {
+  fn from_opt(opt: &Opt) -> Option<T> {
+    opt.value()
+  }
+
+  fn validator(_: Option<Option<T>>) -> Option<fn(&str) -> Result<(), ~str>> {
+    Some(parses::<T>)
+  }
+}
+
+/// All the values given to the option.
+impl<T: FromStr> FromOpt for Vec<T> {
+  fn from_opt(opt: &Opt) -> Vec<T> {
+    opt.values().filter_map(|value| value).collect()
+  }
+
+  fn validator(_: Option<Vec<T>>) -> Option<fn(&str) -> Result<(), ~str>> {
+    Some(parses::<T>)
+  }
+}
+
+/**
+  Declare a struct whose fields are filled from command line options.
+  Each field is given the arguments of add_option(), and its type must
+  implement FromOpt. The struct gets two functions: register(), adding the
+  options to a Context, and from_context() building the struct once the
+  Context was validated. The values of Option<T> and Vec<T> fields are
+  checked by validate(), which fails if one isn't a valid T.
+
+  ```rust
+  cmdparse_options!(struct Options {
+    verbose: uint = (Some("verbose"), Some('v'), Some("Be verbose"), Flags::Defaults),
+    output: Option<~str> = (Some("output"), Some('o'), None, Flags::TakesArg)
+  })
+
+  let mut ctx = Context::new("cmdparse [options]", os::args());
+  Options::register(&mut ctx).unwrap();
+  ctx.validate().unwrap();
+  let options = Options::from_context(&ctx);
+  ```
+*/
+#[macro_export]
+macro_rules! cmdparse_options(
+  (struct $name:ident {
+    $($field:ident : $ty:ty = ($lname:expr, $sname:expr, $descr:expr, $flags:expr)),+
+  }) => (
+    pub struct $name {
+      $(pub $field: $ty),+
+    }
+
+    impl $name {
+      pub fn register(ctx: &mut ::cmdparse::Context) -> Result<(), &'static str> {
+        use cmdparse::OptGroup;
+        $(
+          let opt = try!(ctx.add_option($lname, $sname, $descr, $flags));
+          match ::cmdparse::FromOpt::validator(None::<$ty>) {
+            Some(validator) => opt.add_validator(validator),
+            None => {}
+          }
+        )+
+        Ok(())
+      }
+
+      pub fn from_context(ctx: &::cmdparse::Context) -> $name {
+        $name {
+          $($field: ::cmdparse::FromOpt::from_opt(&ctx.find_option($lname, $sname).unwrap())),+
+        }
+      }
+    }
+  )
+)
+
 trait WithCtx {
   fn get_inner<'a>(&'a mut self) -> &'a mut LocalContext;
 }
@@ -105,63 +368,183 @@ pub trait OptGroup: WithCtx {
     self.get_inner().add_option(lname, sname, description, flags)
   }
 
+  /// Variant of add_option() for names and descriptions only known at
+  /// runtime, eg options declared by plugins. Every add_*() method taking
+  /// optional names has such an add_owned_*() variant. Unlike add_lopt() or
+  /// add_command(), they can't accept both kinds of strings, since the type
+  /// of a None name couldn't be inferred.
+  fn add_owned_option(&mut self, lname: Option<~str>, sname: Option<char>,
+                      description: Option<~str>, flags: uint) -> Result<Opt, &'static str> {
+    self.get_inner().add_option(lname, sname, description, flags)
+  }
+
   /// Helper function to add a long option with Flags::Default.
   /// Fails if an option with the same name already exists.
-  fn add_lopt(&mut self, name: &'static str, description: &'static str) -> Opt {
+  fn add_lopt<N: IntoMaybeOwned<'static>, D: IntoMaybeOwned<'static>>(&mut self, name: N,
+                                                                      description: D) -> Opt {
     self.get_inner().add_option(Some(name), None, Some(description),
                               Flags::Defaults).unwrap()
   }
 
   /// Helper function to add a short option with Flags::Default.
   /// Fails if an option with the same name already exists.
-  fn add_sopt(&mut self, name: char, description: &'static str) -> Opt {
-    self.get_inner().add_option(None, Some(name), Some(description),
+  fn add_sopt<D: IntoMaybeOwned<'static>>(&mut self, name: char, description: D) -> Opt {
+    self.get_inner().add_option(None::<&'static str>, Some(name), Some(description),
                               Flags::Defaults).unwrap()
   }
 
   /// Helper function to add an option, which has both a long and a short name,
   /// with Flags::Default.
   /// Fails if an option with the same names already exists.
-  fn add_opt(&mut self, lname: &'static str, sname: char,
-             description: &'static str) -> Opt {
+  fn add_opt<N: IntoMaybeOwned<'static>, D: IntoMaybeOwned<'static>>(&mut self, lname: N,
+                                                                    sname: char,
+                                                                    description: D) -> Opt {
     self.get_inner().add_option(Some(lname), Some(sname), Some(description),
                               Flags::Defaults).unwrap()
   }
+
+  /// Add an option whose values must be valid T. Invalid values are reported
+  /// by Context::validate(). The option takes an argument even if flags
+  /// contains neither Flags::TakesArg nor Flags::TakesOptionalArg.
+  /// Return Err() for the same reasons as add_option().
+  fn add_typed_option<T: FromStr + Send>(&mut self, lname: Option<&'static str>,
+                                  sname: Option<char>, description: Option<&'static str>,
+                                  flags: uint) -> Result<TypedOpt<T>, &'static str> {
+    self.get_inner().add_typed_option(lname, sname, description, flags)
+  }
+
+  /// Variant of add_typed_option() taking owned strings, see add_owned_option().
+  fn add_owned_typed_option<T: FromStr + Send>(&mut self, lname: Option<~str>, sname: Option<char>,
+                                        description: Option<~str>,
+                                        flags: uint) -> Result<TypedOpt<T>, &'static str> {
+    self.get_inner().add_typed_option(lname, sname, description, flags)
+  }
+
+  /// Add an option taking key=value entries, eg -Dname=value or --set name=value.
+  /// Entries without '=' or with an empty key are reported by
+  /// Context::validate(), as are keys given several times if duplicates
+  /// is RejectDuplicates. Return Err() for the same reasons as add_option().
+  fn add_map_option(&mut self, lname: Option<&'static str>, sname: Option<char>,
+                    description: Option<&'static str>, duplicates: DuplicateKeys)
+                    -> Result<Opt, &'static str> {
+    self.get_inner().add_map_option(lname, sname, description, duplicates)
+  }
+
+  /// Variant of add_map_option() taking owned strings, see add_owned_option().
+  fn add_owned_map_option(&mut self, lname: Option<~str>, sname: Option<char>,
+                          description: Option<~str>, duplicates: DuplicateKeys)
+                          -> Result<Opt, &'static str> {
+    self.get_inner().add_map_option(lname, sname, description, duplicates)
+  }
+
+  /// Add an option counting how many times it was given, up to max.
+  /// A level can also be given explicitly, eg --verbose=2.
+  /// Return Err() for the same reasons as add_option().
+  fn add_counter(&mut self, lname: Option<&'static str>, sname: Option<char>,
+                 description: Option<&'static str>, max: uint) -> Result<Opt, &'static str> {
+    self.get_inner().add_counter(lname, sname, description, max)
+  }
+
+  /// Variant of add_counter() taking owned strings, see add_owned_option().
+  fn add_owned_counter(&mut self, lname: Option<~str>, sname: Option<char>,
+                       description: Option<~str>, max: uint) -> Result<Opt, &'static str> {
+    self.get_inner().add_counter(lname, sname, description, max)
+  }
+
+  /// Add an option decrementing the level of a counter, eg -q for -v.
+  /// Its level is bounded by -max. Return Err() if counter wasn't added
+  /// with add_counter() or for the same reasons as add_option().
+  fn add_decrement(&mut self, counter: &Opt, lname: Option<&'static str>,
+                   sname: Option<char>, description: Option<&'static str>)
+                   -> Result<Opt, &'static str> {
+    self.get_inner().add_decrement(counter, lname, sname, description)
+  }
+
+  /// Variant of add_decrement() taking owned strings, see add_owned_option().
+  fn add_owned_decrement(&mut self, counter: &Opt, lname: Option<~str>, sname: Option<char>,
+                         description: Option<~str>) -> Result<Opt, &'static str> {
+    self.get_inner().add_decrement(counter, lname, sname, description)
+  }
+
+  /// Add other names for an option previously added to this group. The
+  /// aliases share the results of the option. Use Flags::Hidden to keep
+  /// them out of the help message. Return Err() if the alias has neither
+  /// short nor long name or if one of its names is already used.
+  fn add_alias(&mut self, opt: &Opt, lname: Option<&'static str>,
+               sname: Option<char>, flags: uint) -> Result<(), &'static str> {
+    self.get_inner().add_alias(opt, lname, sname, flags)
+  }
+
+  /// Variant of add_alias() taking owned strings, see add_owned_option().
+  fn add_owned_alias(&mut self, opt: &Opt, lname: Option<~str>,
+                     sname: Option<char>, flags: uint) -> Result<(), &'static str> {
+    self.get_inner().add_alias(opt, lname, sname, flags)
+  }
 }
 
 pub struct Context {
-  // The arguments provided by the user.
+  // The program name, ie the first input argument.
+  program_name: Option<~str>,
+  // The arguments provided by the user, without the program name. They
+  // might not be valid UTF-8.
+  args: Vec<~[u8]>,
+  // The arguments split into options and values, by validate().
   raw_args: Vec<RawArg>,
   // The arguments left after validation
   residual_args: Vec<~str>,
+  // The bytes of these arguments
+  raw_residual_args: Vec<~[u8]>,
   // The context containing all the global options.
   inner_ctx: LocalContext,
   // The map of the authorized commands.
-  commands: HashMap<&'static str, Cmd>,
+  commands: HashMap<~str, Cmd>,
+  // The map of command aliases to command names.
+  cmd_aliases: HashMap<~str, ~str>,
+  // Settings for the parsing, see CtxFlags.
+  flags: uint,
+  // The version printed by the option of set_version().
+  version: Option<MaybeOwned<'static>>,
+  // Where the version is printed, the standard output if None.
+  version_writer: Option<~Writer>,
+  // Whether enable_auto_help() was called, and whether it added the help command.
+  auto_help: bool,
+  help_command: bool,
 }
 
+// Each argument keeps its position in the input arguments. Short options
+// also keep the index of the word they were grouped in, since the words of
+// a response file share the position of the @file argument.
 enum RawArg {
-  Short(char),
-  Long(~str),
-  Neither(~str),
+  Short(char, uint, uint),
+  Long(~str, uint),
+  // Values keep the bytes of the input, which might not be valid UTF-8.
+  Neither(~[u8], uint),
+  // Value given after '=' in a long option
+  Attached(~[u8], uint),
 }
 
 #[deriving(Show)]
 struct LocalContext {
   alignment: uint,
   // A summary describing the application and/or an exemple.
-  description: &'static str,
+  description: MaybeOwned<'static>,
   // Maps of locally valid options short/long.
-  loptions: HashMap<&'static str, Opt>,
+  loptions: HashMap<~str, Opt>,
   soptions: HashMap<char, Opt>,
   // List of options added. Needed for print_help
   print_options: Vec<Opt>,
+  // The help option added by Context::enable_auto_help()
+  help: Option<Opt>,
+  // The version option added by Context::set_version()
+  version: Option<Opt>,
 }
 
 #[deriving(Show)]
 pub struct Cmd {
   inner_ctx: LocalContext,
   result: CmdRes,
+  // Aliases shown in print_help
+  aliases: Vec<~str>,
 }
 
 #[deriving(Clone)]
@@ -170,10 +553,82 @@ pub struct CmdRes(Rc<RefCell<bool>>);
 #[deriving(Clone,Show)]
 pub struct Opt {
   short_name: Option<char>,
-  long_name: Option<&'static str>,
-  description: Option<&'static str>,
+  long_name: Option<MaybeOwned<'static>>,
+  description: Option<MaybeOwned<'static>>,
   flags: uint,
+  // Whether this is the --no- variant of a negatable option,
+  // or the decrementing option of a counter.
+  negated: bool,
   result: Rc<RefCell<Res>>,
+  conf: Rc<RefCell<OptConf>>,
+}
+
+/// Error returned when the value of an option isn't of the requested type.
+#[deriving(Clone, Eq)]
+pub struct ValueError {
+  /// Name of the option, eg --output or -o.
+  pub option: ~str,
+  /// The value which couldn't be converted.
+  pub value: ~str,
+  /// Position of the value in the input arguments, the program name being 0.
+  pub position: uint,
+}
+
+/// A value given to an option, along with the result of its conversion.
+#[deriving(Clone, Eq, Show)]
+pub struct ParsedValue<T> {
+  /// The value as it was given.
+  pub raw: ~str,
+  /// Position of the value in the input arguments, the program name being 0.
+  pub position: uint,
+  /// The converted value, None if raw isn't a valid T.
+  pub value: Option<T>,
+}
+
+/// Copy of the results of a validation, see Context::result(). It doesn't
+/// share anything with the context, so it can be sent to another task.
+#[deriving(Clone, Eq, Show)]
+pub struct ParseResult {
+  options: Vec<OptResult>,
+  command: Option<~str>,
+  args: Vec<~str>,
+  raw_args: Vec<~[u8]>,
+}
+
+/// Results of one option in a ParseResult.
+#[deriving(Clone, Eq, Show)]
+pub struct OptResult {
+  /// Id of the option, see Opt::id().
+  pub id: uint,
+  /// Name of the option, eg --output or -o.
+  pub name: ~str,
+  /// Command the option belongs to, None for global options.
+  pub command: Option<~str>,
+  /// Every name accepted for the option, including the aliases.
+  pub long_names: Vec<~str>,
+  /// Same for the short names.
+  pub short_names: Vec<char>,
+  /// Number of times the option was given.
+  pub count: uint,
+  /// Values given to the option, with invalid UTF-8 replaced.
+  pub values: Vec<~str>,
+  /// Bytes of these values, see Opt::raw_values().
+  pub raw_values: Vec<~[u8]>,
+  /// Positions of these values in the input arguments.
+  pub positions: Vec<uint>,
+  /// Last state given to a negatable option, see Opt::enabled().
+  pub enabled: Option<bool>,
+  /// Level of a counter, see Opt::level().
+  pub level: int,
+  /// Whether the option was added with Flags::Secret.
+  pub secret: bool,
+}
+
+/// Handle on an option added with add_typed_option().
+#[deriving(Clone)]
+pub struct TypedOpt<T> {
+  opt: Opt,
+  marker: marker::CovariantType<T>,
 }
 
 impl<'a> Show for &'a mut Cmd {
@@ -182,6 +637,13 @@ impl<'a> Show for &'a mut Cmd {
   }
 }
 
+impl Show for ValueError {
+  fn fmt(&self, formatter: &mut Formatter) -> fmt::Result {
+    write!(formatter.buf, "Invalid type for value '{:s}' of option {:s} (argument {:u})",
+           self.value, self.option, self.position)
+  }
+}
+
 impl Show for CmdRes {
   fn fmt(&self, formatter: &mut Formatter) -> fmt::Result {
     match self {
@@ -196,86 +658,387 @@ impl Show for Rc<RefCell<Res>> {
   }
 }
 
+impl Show for Rc<RefCell<OptConf>> {
+  fn fmt(&self, formatter: &mut Formatter) -> fmt::Result {
+    self.borrow().fmt(formatter)
+  }
+}
+
+impl Show for ~Validator {
+  fn fmt(&self, formatter: &mut Formatter) -> fmt::Result {
+    formatter.buf.write_str("<validator>")
+  }
+}
+
+impl Show for Parser {
+  fn fmt(&self, formatter: &mut Formatter) -> fmt::Result {
+    formatter.buf.write_str("<parser>")
+  }
+}
+
 #[deriving(Show)]
 struct Res {
   passed: uint,        // Number of time we've seen this option
   values: Vec<~str>,     // Arguments it's been given
+  raw_values: Vec<~[u8]>, // Their bytes, which might not be valid UTF-8
+  positions: Vec<uint>,  // Positions of these arguments in the input
+  groups: Vec<uint>,     // Number of arguments given to each occurrence
+  parsed: Vec<~Any>,     // Converted arguments, for typed options only
+  enabled: Option<bool>, // Last state given to a negatable option
+  level: int,            // Level of a counter
+}
+
+// Settings of an option which can be changed after it was added.
+#[deriving(Show)]
+struct OptConf {
+  validators: Vec<~Validator>,
+  // Conversion of the values, for typed options only
+  parser: Option<Parser>,
+  // Aliases shown in print_help
+  long_aliases: Vec<MaybeOwned<'static>>,
+  short_aliases: Vec<char>,
+  // Maximum level, for counters only
+  max_level: Option<uint>,
+  // Number of values per occurrence, if not given by the flags
+  arity: Option<Arity>,
+  // Character separating several values given in one argument
+  delimiter: Option<char>,
+  // For map options only, the policy for duplicate keys and the key validators
+  map: Option<DuplicateKeys>,
+  key_validators: Vec<~Validator>,
+  // Id shared by the option, its aliases and its --no- or decrementing variants
+  id: uint,
+}
+
+impl Res {
+  fn new() -> Res {
+    Res { passed: 0, values: Vec::new(), raw_values: Vec::new(), positions: Vec::new(),
+          groups: Vec::new(), parsed: Vec::new(), enabled: None, level: 0 }
+  }
 }
 
 impl Context {
   pub fn new(description: &'static str, args: ~[~str]) -> Context {
+    Context::from_iter(description, args.move_iter(), true)
+  }
+
+  /// Variant of new() taking the input arguments from any iterator of
+  /// strings. If program_name is true, the first item is the program name
+  /// and isn't parsed.
+  pub fn from_iter<S: Str, I: Iterator<S>>(description: &'static str, args: I,
+                                           program_name: bool) -> Context {
+    Context::from_bytes(description, args.map(|arg| arg.as_slice().as_bytes().to_owned()),
+                        program_name)
+  }
+
+  /// Variant of from_iter() taking arguments which might not be valid
+  /// UTF-8, eg file names. Option names must still be valid UTF-8.
+  pub fn from_bytes<I: Iterator<~[u8]>>(description: &'static str, mut args: I,
+                                        program_name: bool) -> Context {
+    let program_name = if program_name {
+      args.next().map(|name| lossy(name.as_slice()))
+    } else {
+      None
+    };
     Context {
-      raw_args: Context::prep_args(args),
+      program_name: program_name,
+      args: args.collect(),
+      raw_args: Vec::new(),
       residual_args: Vec::new(),
+      raw_residual_args: Vec::new(),
       inner_ctx: LocalContext::new(description),
       commands: HashMap::new(),
+      cmd_aliases: HashMap::new(),
+      flags: CtxFlags::Defaults,
+      version: None,
+      version_writer: None,
+      auto_help: false,
+      help_command: false,
+    }
+  }
+
+  /// Variant of new() splitting a whole command line into arguments with
+  /// split_words(). Return Err() if the line can't be split.
+  pub fn from_command_line(description: &'static str, line: &str,
+                           program_name: bool) -> Result<Context, ~str> {
+    let args = try!(split_words(line));
+    Ok(Context::from_iter(description, args.move_iter(), program_name))
+  }
+
+  /// Variant of new() taking the arguments of the current process.
+  pub fn from_env(description: &'static str) -> Context {
+    Context::from_bytes(description, os::args_as_bytes().move_iter(), true)
+  }
+
+  /// Change the parsing settings, using the values in CtxFlags.
+  pub fn set_flags(&mut self, flags: uint) {
+    self.flags = flags;
+  }
+
+  /// Add -h/--help to the context and to every command, including the
+  /// commands added later, and a help command taking an optional command
+  /// name. See validate_outcome() for how the help is reported. The names
+  /// already used are left alone, so the options named -h or --help and the
+  /// command named help must be added before this call. Calling it again
+  /// does nothing.
+  pub fn enable_auto_help(&mut self) {
+    if self.auto_help {
+      return;
+    }
+    self.auto_help = true;
+    self.inner_ctx.add_help();
+    for cmd in self.commands.mut_values() {
+      cmd.inner_ctx.add_help();
+    }
+    self.help_command = self.add_command("help", "Print the help of a command").is_ok();
+  }
+
+  /// Add the -V/--version option. When it is given before the command, if
+  /// any, validate() prints version and returns Ok() without checking the
+  /// arguments after it. Return the option, or Err() if -V or --version is
+  /// already used.
+  pub fn set_version<V: IntoMaybeOwned<'static>>(&mut self,
+                                                 version: V) -> Result<Opt, &'static str> {
+    // Check both names first, so that nothing is added on error
+    if self.inner_ctx.loptions.find_equiv(&"version").is_some() ||
+       self.inner_ctx.soptions.contains_key(&'V') {
+      return Err("An option with the same name was already added");
     }
+    let opt = try!(self.inner_ctx.add_option(Some("version"), Some('V'),
+                                             Some("Print the version"), Flags::Defaults));
+    self.version = Some(version.into_maybe_owned());
+    self.inner_ctx.version = Some(opt.clone());
+    Ok(opt)
+  }
+
+  /// Print the version with writer instead of the standard output.
+  pub fn set_version_writer(&mut self, writer: ~Writer) {
+    self.version_writer = Some(writer);
   }
 
-  fn prep_args(args: ~[~str]) -> Vec<RawArg> {
-    let mut vect = Vec::new();
+  // Print the version given to set_version().
+  fn print_version(&mut self) -> Result<(), ~str> {
+    let line = match self.version {
+      Some(ref version) => format!("{:s}\n", version.as_slice()),
+      None => return Ok(()),
+    };
+    let written = match self.version_writer {
+      Some(ref mut writer) => writer.write_str(line),
+      None => io::stdout().write_str(line),
+    };
+    written.map_err(|err| format!("Can't print the version : {}", err))
+  }
 
-    // skip the program name
-    for arg in args.move_iter().skip(1) {
-      if arg.starts_with("--") {
-        // Long option
-        let mut cit = arg.slice_from(2).splitn('=', 1);
-        cit.next().and_then(|ovalue| {
-          vect.push(Long(ovalue.to_owned()));
-          cit.next()
-        }).map(|ovalue| vect.push(Neither(ovalue.to_owned())));
-      } else if arg.starts_with("-") {
-        // Short option(s)
-        for c in arg.chars().skip(1) {
-          vect.push(Short(c));
+  // Split the input arguments into options and values, the last argument
+  // first. With CtxFlags::ResponseFiles, the response files are expanded.
+  fn prep_args(&self) -> Result<Vec<RawArg>, ~str> {
+    let (mut vect, mut word) = (Vec::new(), 0);
+
+    for (i, arg) in self.args.iter().enumerate() {
+      // The program name is at position 0
+      let pos = i + 1;
+      if (self.flags & CtxFlags::ResponseFiles) != 0 && is_response_file(arg.as_slice()) {
+        let file_args = try!(read_response_file(arg.slice_from(1), &mut Vec::new()));
+        for file_arg in file_args.move_iter() {
+          try!(push_raw_arg(&mut vect, file_arg, pos, word));
+          word += 1;
         }
       } else {
-        vect.push(Neither(arg));
+        try!(push_raw_arg(&mut vect, arg.clone(), pos, word));
+        word += 1;
       }
     }
 
     vect.reverse();
-    vect
+    Ok(vect)
   }
 
   /// Specify valid commands for your program. Use the 'op' parameters to add
   /// the options for this command. Fail if a command with the same name
   /// was already added.
-  pub fn add_cmd_with<T>(&mut self, name: &'static str,
-                         description: &'static str,
-                         op: |cmd: &mut Cmd| -> T) -> (CmdRes, T) {
+  pub fn add_cmd_with<T, N: IntoMaybeOwned<'static>, D: IntoMaybeOwned<'static>>(
+                      &mut self, name: N, description: D,
+                      op: |cmd: &mut Cmd| -> T) -> (CmdRes, T) {
     let (res, cmd) = self.add_command(name, description).unwrap();
     (res, op(cmd))
   }
 
-  /// Specify valid commands for your program. Return Err() if
-  /// an option with the same name was already added.
-  pub fn add_command<'a>(&'a mut self, name: &'static str,
-                     description: &'static str)
+  /// Specify valid commands for your program. The name and description can
+  /// be static or owned strings. Return Err() if a command with the same
+  /// name was already added.
+  pub fn add_command<'a, N: IntoMaybeOwned<'static>, D: IntoMaybeOwned<'static>>(
+                     &'a mut self, name: N, description: D)
                      -> Result<(CmdRes, &'a mut Cmd), &'static str> {
-
-    if !self.commands.insert(name, Cmd::new(description)) {
+    let name = name.into_maybe_owned().into_owned();
+    if self.cmd_aliases.contains_key(&name) ||
+       !self.commands.insert(name.clone(), Cmd::new(description)) {
       return Err("This command was already added");
     }
 
     // Is there a better way to get a mut ref to the value we've just
     // inserted, without doing a lookup ?
     let cmd = self.commands.get_mut(&name);
+    if self.auto_help {
+      cmd.inner_ctx.add_help();
+    }
     Ok((cmd.result.clone(), cmd))
   }
 
+  /// Add another name for a command previously added. The alias is left out
+  /// of the help message if flags contains Flags::Hidden. Return Err() if
+  /// there is no such command or if the alias is already used by a command.
+  pub fn add_command_alias<N: Str, A: IntoMaybeOwned<'static>>(&mut self, name: N, alias: A,
+                                                               flags: uint)
+                                                               -> Result<(), &'static str> {
+    let (name, alias) = (name.into_owned(), alias.into_maybe_owned().into_owned());
+    if self.commands.contains_key(&alias) || self.cmd_aliases.contains_key(&alias) {
+      return Err("A command with the same name was already added");
+    }
+
+    match self.commands.find_mut(&name) {
+      Some(cmd) => if (flags & Flags::Hidden) == 0 {
+        cmd.aliases.push(alias.clone());
+      },
+      None => return Err("There is no command with this name"),
+    }
+    self.cmd_aliases.insert(alias, name);
+    Ok(())
+  }
+
   /// Validate the input arguments against the options specified via add_option().
-  /// Return an Err() when the input isn't valid.
+  /// Return an Err() when the input isn't valid. The results of a previous
+  /// validation are cleared first. See set_version() for the version option.
+  /// With enable_auto_help(), requesting the help gives an Err() too, so
+  /// use validate_outcome() instead, see the crate documentation.
   pub fn validate(&mut self) -> Result<(), ~str> {
-    self.inner_ctx.parse(&mut self.commands, &mut self.raw_args,
-                         &mut self.residual_args)
+    match try!(self.validate_outcome()) {
+      HelpRequested(_) => Err(~"The help was requested."),
+      Proceed | VersionPrinted => Ok(()),
+    }
+  }
+
+  /// Variant of validate() telling whether the version was printed or the
+  /// help was requested with the options or the command added by
+  /// enable_auto_help(). The help options stop the parsing, like an
+  /// invalid argument would: the arguments before them are checked, the
+  /// ones after them aren't.
+  pub fn validate_outcome(&mut self) -> Result<Outcome, ~str> {
+    self.reset();
+    self.raw_args = try!(self.prep_args());
+    let mut residual_args = Vec::new();
+    match try!(self.inner_ctx.parse(self.flags, &mut self.commands, &self.cmd_aliases,
+                                    &mut self.raw_args, &mut residual_args)) {
+      Proceed => {}
+      VersionPrinted => return self.print_version().map(|_| VersionPrinted),
+      outcome => return Ok(outcome),
+    }
+    if self.help_command && self.commands.get(&~"help").result.check() {
+      return self.help_target(residual_args.as_slice().head());
+    }
+    // Without a command, a first argument close to a command name is most
+    // likely a typo.
+    let check_typos = (self.flags & CtxFlags::RejectCommandTypos) != 0 &&
+                      !self.commands.values().any(|cmd| cmd.result.check());
+    let near_miss = match residual_args.as_slice().head() {
+      Some(&Neither(ref bytes, _)) | Some(&Attached(ref bytes, _)) if check_typos => {
+        let names = self.commands.keys().chain(self.cmd_aliases.keys());
+        suggest(lossy(bytes.as_slice()).as_slice(), names.map(|name| name.as_slice())).is_some()
+      }
+      _ => false,
+    };
+    if near_miss {
+      return Err(unexpected_arg(&self.commands, &self.cmd_aliases, &mut residual_args));
+    }
+    for arg in residual_args.move_iter() {
+      let bytes = arg.bytes();
+      self.residual_args.push(lossy(bytes.as_slice()));
+      self.raw_residual_args.push(bytes);
+    }
+    Ok(Proceed)
+  }
+
+  // The outcome of the help command, given the argument naming a command.
+  fn help_target(&self, arg: Option<&RawArg>) -> Result<Outcome, ~str> {
+    let name = match arg {
+      Some(&Neither(ref value, _)) | Some(&Attached(ref value, _)) => lossy(value.as_slice()),
+      _ => return Ok(HelpRequested(None)),
+    };
+    match find_command(&self.commands, &self.cmd_aliases, name.as_slice()) {
+      Some(cmd_name) => Ok(HelpRequested(Some(cmd_name))),
+      None => Err(format!("Invalid command : {:s}.", name)),
+    }
+  }
+
+  /// Validate another list of arguments, eg a line read by an interactive
+  /// shell, against the same options and commands. Unlike in new(), args
+  /// doesn't start with the program name. See validate().
+  pub fn parse_args(&mut self, args: ~[~str]) -> Result<(), ~str> {
+    self.args = args.move_iter().map(|arg| arg.into_bytes()).collect();
+    self.validate()
+  }
+
+  /// Variant of parse_args() splitting a whole command line into arguments
+  /// with split_words().
+  pub fn parse_line(&mut self, line: &str) -> Result<(), ~str> {
+    let args = try!(split_words(line));
+    self.args = args.move_iter().map(|arg| arg.into_bytes()).collect();
+    self.validate()
+  }
+
+  // Clear the results of every option and command, and the residual arguments.
+  fn reset(&mut self) {
+    self.raw_args.clear();
+    self.residual_args.clear();
+    self.raw_residual_args.clear();
+    self.inner_ctx.reset();
+    for cmd in self.commands.values() {
+      cmd.result.reset();
+      cmd.inner_ctx.reset();
+    }
+  }
+
+  /// Return the global option with the given long name or, failing
+  /// that, with the given short name.
+  pub fn find_option(&self, lname: Option<&str>, sname: Option<char>) -> Option<Opt> {
+    lname.and_then(|name| self.inner_ctx.loptions.find_equiv(&name))
+         .or_else(|| sname.and_then(|name| self.inner_ctx.soptions.find(&name)))
+         .map(|opt| opt.clone())
   }
 
-  /// Get an array containing the residual arguments.
+  /// Get an array containing the residual arguments. Invalid UTF-8 is
+  /// replaced, see get_raw_args().
   pub fn get_args<'a>(&'a mut self) -> &'a mut Vec<~str> {
     &mut self.residual_args
   }
 
+  /// Get the bytes of the residual arguments, as given in the input.
+  pub fn get_raw_args<'a>(&'a self) -> &'a Vec<~[u8]> {
+    &self.raw_residual_args
+  }
+
+  /// Return a copy of the results of the last validation: the options of
+  /// the context and of its commands, the command given and the residual
+  /// arguments.
+  pub fn result(&self) -> ParseResult {
+    let mut options = self.inner_ctx.results(None);
+    let mut command = None;
+    for (name, cmd) in self.commands.iter() {
+      options.push_all_move(cmd.inner_ctx.results(Some(name.as_slice())));
+      if cmd.result.check() {
+        command = Some(name.clone());
+      }
+    }
+    options.as_mut_slice().sort_by(|a, b| a.id.cmp(&b.id));
+    ParseResult { options: options, command: command, args: self.residual_args.clone(),
+                  raw_args: self.raw_residual_args.clone() }
+  }
+
+  /// Variant of validate() returning a copy of the results, see result().
+  pub fn parse(&mut self) -> Result<ParseResult, ~str> {
+    try!(self.validate());
+    Ok(self.result())
+  }
+
   pub fn print_help(&self, msg: Option<&str>) {
     match msg {
       Some(err) => println!("Error : {:s}", err), None => {}
@@ -293,9 +1056,13 @@ impl Context {
     if self.commands.len() > 0 {
       println!("\nValid commands :");
       for (name, cmd) in self.commands.iter() {
-        println!("  {:s}    {:s}", *name, cmd.inner_ctx.description);
+        let mut names = Vec::new();
+        names.push(name.clone());
+        names.push_all(cmd.aliases.as_slice());
+        println!("  {:s}    {:s}", names.as_slice().connect(", "),
+                 cmd.inner_ctx.description.as_slice());
         if cmd.inner_ctx.print_options.len() > 0 {
-          println!("    Valid options for {:s} :", *name);
+          println!("    Valid options for {:s} :", name.as_slice());
           for opt in cmd.inner_ctx.print_options.iter() {
             cmd.inner_ctx.print_opt(opt, "    ");
           }
@@ -304,90 +1071,590 @@ impl Context {
       }
     }
   }
+
+  /// Variant of print_help() for one command, given by its name or one of
+  /// its aliases: its usage line, its description, its options and the
+  /// global options. Return Err() if there is no such command.
+  pub fn print_command_help(&self, name: &str, msg: Option<&str>) -> Result<(), &'static str> {
+    let name = match find_command(&self.commands, &self.cmd_aliases, name) {
+      Some(name) => name,
+      None => return Err("There is no command with this name"),
+    };
+    let cmd = self.commands.get(&name);
+    match msg {
+      Some(err) => println!("Error : {:s}", err), None => {}
+    }
+
+    let mut usage = Vec::new();
+    self.program_name.as_ref().map(|program| usage.push(program.as_slice()));
+    if self.inner_ctx.print_options.len() > 0 {
+      usage.push("[global options]");
+    }
+    usage.push(name.as_slice());
+    if cmd.inner_ctx.print_options.len() > 0 {
+      usage.push("[options]");
+    }
+    print!("Usage: \n  ");
+    println!("{:s}", usage.as_slice().connect(" "));
+    println!("\n{:s}", cmd.inner_ctx.description.as_slice());
+    if cmd.aliases.len() > 0 {
+      println!("Aliases : {:s}", cmd.aliases.as_slice().connect(", "));
+    }
+
+    if cmd.inner_ctx.print_options.len() > 0 {
+      println!("\nValid options for {:s} :", name.as_slice());
+      for opt in cmd.inner_ctx.print_options.iter() {
+        cmd.inner_ctx.print_opt(opt, "  ");
+      }
+    }
+    if self.inner_ctx.print_options.len() > 0 {
+      println!("\nValid global options :");
+      for opt in self.inner_ctx.print_options.iter() {
+        self.inner_ctx.print_opt(opt, "  ");
+      }
+    }
+    Ok(())
+  }
 }
 
 impl RawArg {
   pub fn option(&self) -> bool {
     match *self {
-      Neither(_) => false, _ => true
+      Neither(..) | Attached(..) => false, _ => true
     }
   }
 
+  pub fn position(&self) -> uint {
+    match *self {
+      Short(_, pos, _) | Long(_, pos) | Neither(_, pos) | Attached(_, pos) => pos,
+    }
+  }
+
+  // The value with invalid UTF-8 replaced.
   pub fn value(self) -> ~str {
     match self {
-      Short(c) => c.to_str(), Long(a) | Neither(a) => a,
+      Short(c, _, _) => c.to_str(), Long(a, _) => a,
+      Neither(bytes, _) | Attached(bytes, _) => lossy(bytes.as_slice()),
+    }
+  }
+
+  pub fn bytes(self) -> ~[u8] {
+    match self {
+      Short(c, _, _) => c.to_str().into_bytes(), Long(a, _) => a.into_bytes(),
+      Neither(bytes, _) | Attached(bytes, _) => bytes,
     }
   }
 }
 
-enum IsOpt<T, E> {
-  O(T),
-  NotO(E),
+// Decode bytes as UTF-8, replacing the invalid sequences.
+fn lossy(bytes: &[u8]) -> ~str {
+  str::from_utf8_lossy(bytes).into_owned()
 }
 
-impl LocalContext {
-  pub fn new(description: &'static str) -> LocalContext {
-    LocalContext {
-      alignment: min_align,
-      description: description,
-      loptions: HashMap::new(),
-      soptions: HashMap::new(),
-      print_options: Vec::new(),
+// Add the options and/or value in arg to vect. pos is the position of arg
+// in the input arguments and word its index among the expanded arguments.
+// Return Err() if an option name isn't valid UTF-8.
+fn push_raw_arg(vect: &mut Vec<RawArg>, arg: ~[u8], pos: uint,
+                word: uint) -> Result<(), ~str> {
+  if arg.starts_with(bytes!("--")) {
+    // Long option, the value after '=' is kept as bytes
+    let (name, value) = match arg.iter().position(|b| *b == '=' as u8) {
+      Some(i) => (arg.slice(2, i), Some(arg.slice_from(i + 1))),
+      None => (arg.slice_from(2), None),
+    };
+    match str::from_utf8(name) {
+      Some(name) => vect.push(Long(name.to_owned(), pos)),
+      None => return Err(format!("Invalid option : {:s}.", lossy(arg.as_slice()))),
     }
+    value.map(|value| vect.push(Attached(value.to_owned(), pos)));
+  } else if arg.starts_with(bytes!("-")) {
+    // Short option(s)
+    match str::from_utf8(arg.slice_from(1)) {
+      Some(names) => for c in names.chars() { vect.push(Short(c, pos, word)); },
+      None => return Err(format!("Invalid option : {:s}.", lossy(arg.as_slice()))),
+    }
+  } else {
+    vect.push(Neither(arg, pos));
   }
+  Ok(())
+}
 
-  fn parse(&mut self, cmds: &mut HashMap<&'static str, Cmd>,
-           rargs: &mut Vec<RawArg>, residual_args: &mut Vec<~str>) -> Result<(), ~str> {
-    while rargs.len() > 0 {
-      let raw_arg = rargs.pop().unwrap(); // Can't fail since len() > 0;
-      match match match raw_arg {
-        Short(sname) => (O(self.soptions.find(&sname)), sname.to_str()),
-        Long(lname) => (O(self.loptions.find_equiv(&lname.as_slice())), lname),
-        Neither(nname) => (NotO(unsafe {
-          // FIXME: replace transmute with find_mut_equiv or
-          // equivalent once it is added to libstd
-          cmds.find_mut(&transmute(nname.as_slice()))
-        }), nname),
-      } {
-        (O(None), name) => Err(format!("Invalid option : {:s}.", name)),
-        (NotO(None), name) => { residual_args.push(name); Ok(()) }
-        (O(Some(opt)), name) => opt.validate(name, rargs, residual_args),
-        (NotO(Some(cmd)), name) => cmd.validate(name, rargs, residual_args),
-      } {
-        Err(msg) => if residual_args.len() != 0 {
-          return Err(format!("Unexpected argument : {:s}.", residual_args.shift().unwrap()));
-        } else {
-          return Err(msg);
-        },
-        Ok(_) => {}
-      }
-    }
-    Ok(())
+fn is_response_file(arg: &[u8]) -> bool {
+  arg.len() > 1 && arg.starts_with(bytes!("@"))
+}
+
+// Read the arguments in the response file name, expanding the nested
+// response files. stack holds the files being read, to detect cycles.
+// The name might not be valid UTF-8, but the content must be.
+fn read_response_file(name: &[u8], stack: &mut Vec<Path>) -> Result<Vec<~[u8]>, ~str> {
+  // Nested response files are relative to the file including them
+  let path = match stack.last() {
+    Some(parent) => parent.dir_path().join(name),
+    None => os::make_absolute(&Path::new(name)),
+  };
+  let name = lossy(name);
+  if stack.contains(&path) {
+    return Err(format!("The response file {:s} includes itself", name));
   }
 
-  fn add_option(&mut self, long_name: Option<&'static str>,
-                short_name: Option<char>, description: Option<&'static str>,
-                flags: uint) -> Result<Opt, &'static str> {
+  let content = match File::open(&path).read_to_end() {
+    Ok(content) => content,
+    Err(err) => return Err(format!("Can't read the response file {:s} : {}", name, err)),
+  };
+  let content = match str::from_utf8(content.as_slice()) {
+    Some(content) => content,
+    None => return Err(format!("Invalid response file {:s} : not valid UTF-8", name)),
+  };
+  let words = match split_words(content) {
+    Ok(words) => words,
+    Err(msg) => return Err(format!("Invalid response file {:s} : {:s}", name, msg)),
+  };
 
-    let opt = Opt::new(long_name, short_name, description, flags,
-                       Rc::new(RefCell::new(Res { passed:0, values: Vec::new() })));
-    match long_name {
-      Some(name) => {
-        // The alignment is used in print_help() to make sure the columns are aligned.
-        self.alignment = ::std::cmp::max(self.alignment, name.len() + min_align);
-        if !self.loptions.insert(name, opt.clone()) {
-          return Err("An option with the same long name was already added");
+  stack.push(path);
+  let mut args = Vec::new();
+  for word in words.move_iter() {
+    if is_response_file(word.as_bytes()) {
+      args.push_all_move(try!(read_response_file(word.as_bytes().slice_from(1), stack)));
+    } else {
+      args.push(word.into_bytes());
+    }
+  }
+  stack.pop();
+  Ok(args)
+}
+
+/// Split line into words at whitespaces, following the quoting rules of
+/// POSIX shells: single quotes, double quotes and backslash escapes.
+/// Return Err() for an unterminated quote or a trailing backslash.
+pub fn split_words(line: &str) -> Result<Vec<~str>, ~str> {
+  let mut words = Vec::new();
+  let mut word = StrBuf::new();
+  // Whether a word was started, to keep the empty words given as '' or "".
+  let mut in_word = false;
+  let mut chars = line.chars();
+  loop {
+    match chars.next() {
+      None => break,
+      Some(c) if c.is_whitespace() => if in_word {
+        words.push(word.into_owned());
+        word = StrBuf::new();
+        in_word = false;
+      },
+      Some('\'') => {
+        in_word = true;
+        loop {
+          match chars.next() {
+            Some('\'') => break,
+            Some(c) => word.push_char(c),
+            None => return Err(~"unterminated single quote"),
+          }
         }
       }
+      Some('"') => {
+        in_word = true;
+        loop {
+          match chars.next() {
+            Some('"') => break,
+            // In double quotes, a backslash only escapes these characters.
+            Some('\\') => match chars.next() {
+              Some('\n') => {}
+              Some(c) if c == '"' || c == '\\' || c == '$' || c == '`' => word.push_char(c),
+              Some(c) => { word.push_char('\\'); word.push_char(c); }
+              None => return Err(~"unterminated double quote"),
+            },
+            Some(c) => word.push_char(c),
+            None => return Err(~"unterminated double quote"),
+          }
+        }
+      }
+      Some('\\') => match chars.next() {
+        Some('\n') => {}
+        Some(c) => { in_word = true; word.push_char(c); }
+        None => return Err(~"trailing backslash"),
+      },
+      Some(c) => { in_word = true; word.push_char(c); }
+    }
+  }
+
+  if in_word {
+    words.push(word.into_owned());
+  }
+  Ok(words)
+}
+
+/// Quote word so that split_words() gives it back unchanged.
+/// Words made only of safe characters are left as they are.
+pub fn quote_word(word: &str) -> ~str {
+  if word.len() > 0 && word.chars().all(|c| c.is_alphanumeric() || "-_./=:,+@%".contains_char(c)) {
+    word.to_owned()
+  } else {
+    // Single quotes can't be escaped within single quotes: close the
+    // quotes, add an escaped quote and open them again.
+    format!("'{:s}'", word.replace("'", "'\\''"))
+  }
+}
+
+/// Quote and join words into a line which split_words() splits back into words.
+pub fn join_words<S: Str>(words: &[S]) -> ~str {
+  let quoted: Vec<~str> = words.iter().map(|word| quote_word(word.as_slice())).collect();
+  quoted.as_slice().connect(" ")
+}
+
+// Build the error message for an option or command given after the first
+// residual argument. Suggest a command if that argument looks like a typo.
+fn unexpected_arg(cmds: &HashMap<~str, Cmd>, aliases: &HashMap<~str, ~str>,
+                  residual_args: &mut Vec<RawArg>) -> ~str {
+  let arg = residual_args.shift().unwrap().value();
+  match suggest(arg.as_slice(), cmds.keys().chain(aliases.keys()).map(|name| name.as_slice())) {
+    Some(name) => format!("Unexpected argument : {:s}. Did you mean {:s}?", arg, name),
+    None => format!("Unexpected argument : {:s}.", arg),
+  }
+}
+
+// Return the name of the command given as name or as one of its aliases.
+fn find_command(cmds: &HashMap<~str, Cmd>, aliases: &HashMap<~str, ~str>,
+                name: &str) -> Option<~str> {
+  let name = resolve_alias(aliases, name);
+  cmds.find_equiv(&name).map(|_| name.to_owned())
+}
+
+// Return the name of the command aliased by name, or name if it isn't an alias.
+fn resolve_alias<'a>(aliases: &'a HashMap<~str, ~str>, name: &'a str) -> &'a str {
+  match aliases.find_equiv(&name) {
+    Some(cmd_name) => cmd_name.as_slice(),
+    None => name,
+  }
+}
+
+// Return the candidate closest to name, if it is close enough to be a typo.
+// Candidates which would have to be replaced entirely, like one-letter
+// aliases, are never suggested.
+fn suggest<'a, I: Iterator<&'a str>>(name: &str, candidates: I) -> Option<&'a str> {
+  let max_distance = ::std::cmp::max(1, name.char_len() / 3);
+  candidates.map(|candidate| (edit_distance(name, candidate), candidate))
+            .filter(|&(distance, candidate)| distance <= max_distance &&
+                                             distance < candidate.char_len())
+            .min_by(|&(distance, _)| distance)
+            .map(|(_, candidate)| candidate)
+}
+
+// Split a key=value entry at the first '='.
+fn split_entry<'a>(entry: &'a str) -> Option<(&'a str, &'a str)> {
+  entry.find('=').map(|i| (entry.slice_to(i), entry.slice_from(i + 1)))
+}
+
+// Split value at each delimiter which isn't escaped with a backslash. The
+// value is split as bytes since it might not be valid UTF-8.
+fn split_escaped(value: &[u8], delimiter: char) -> Vec<~[u8]> {
+  let delimiter = delimiter.to_str().into_bytes();
+  let (delimiter, backslash) = (delimiter.as_slice(), '\\' as u8);
+  let mut values = Vec::new();
+  let mut current = Vec::new();
+  let mut i = 0;
+  while i < value.len() {
+    let rest = value.slice_from(i);
+    if rest[0] == backslash && rest.len() > 1 && rest.slice_from(1).starts_with(delimiter) {
+      current.push_all(delimiter);
+      i += 1 + delimiter.len();
+    } else if rest[0] == backslash && rest.len() > 1 && rest[1] == backslash {
+      current.push(backslash);
+      i += 2;
+    } else if rest.starts_with(delimiter) {
+      values.push(current.move_iter().collect());
+      current = Vec::new();
+      i += delimiter.len();
+    } else {
+      current.push(rest[0]);
+      i += 1;
+    }
+  }
+  values.push(current.move_iter().collect());
+  values
+}
+
+// Levenshtein distance between a and b.
+fn edit_distance(a: &str, b: &str) -> uint {
+  let b_chars: Vec<char> = b.chars().collect();
+  let mut prev: Vec<uint> = range(0, b_chars.len() + 1).collect();
+  for (i, ca) in a.chars().enumerate() {
+    let mut cur = Vec::with_capacity(b_chars.len() + 1);
+    cur.push(i + 1);
+    for (j, cb) in b_chars.iter().enumerate() {
+      let cost = if ca == *cb { 0 } else { 1 };
+      let distance = ::std::cmp::min(::std::cmp::min(*prev.get(j + 1) + 1, *cur.get(j) + 1),
+                                     *prev.get(j) + cost);
+      cur.push(distance);
+    }
+    prev = cur;
+  }
+  *prev.get(b_chars.len())
+}
+
+impl LocalContext {
+  pub fn new<D: IntoMaybeOwned<'static>>(description: D) -> LocalContext {
+    LocalContext {
+      alignment: min_align,
+      description: description.into_maybe_owned(),
+      loptions: HashMap::new(),
+      soptions: HashMap::new(),
+      print_options: Vec::new(),
+      help: None,
+      version: None,
+    }
+  }
+
+  // Parse the arguments until the end, an error, the help or the version option.
+  fn parse(&mut self, flags: uint, cmds: &mut HashMap<~str, Cmd>,
+           aliases: &HashMap<~str, ~str>,
+           rargs: &mut Vec<RawArg>, residual_args: &mut Vec<RawArg>) -> Result<Outcome, ~str> {
+    while rargs.len() > 0 {
+      let raw_arg = rargs.pop().unwrap(); // Can't fail since len() > 0;
+      // Command names are valid UTF-8, other values are kept as bytes.
+      let cmd_name = match raw_arg {
+        Neither(ref nname, _) | Attached(ref nname, _) =>
+          str::from_utf8(nname.as_slice()).and_then(|nname| find_command(&*cmds, aliases, nname)),
+        _ => None,
+      };
+      // Options and commands can't be given after the residual arguments.
+      if residual_args.len() != 0 && (raw_arg.option() || cmd_name.is_some()) {
+        return Err(unexpected_arg(cmds, aliases, residual_args));
+      }
+
+      let position = raw_arg.position();
+      let outcome = match (raw_arg, cmd_name) {
+        (Short(sname, _, word), _) => match self.soptions.find(&sname) {
+          Some(opt) => {
+            try!(opt.validate(sname.to_str(), position, Some(word), rargs));
+            self.outcome_of(opt)
+          }
+          None => return Err(self.invalid_option(sname.to_str())),
+        },
+        (Long(lname, _), _) => match try!(self.find_long(lname.as_slice(), flags)) {
+          Some(opt) => {
+            try!(opt.validate(lname, position, None, rargs));
+            self.outcome_of(opt)
+          }
+          None => return Err(self.invalid_option(lname)),
+        },
+        // The command parses the remaining arguments, the help is its own.
+        (value, Some(cmd_name)) => match try!(cmds.find_mut(&cmd_name).unwrap()
+                                                  .validate(value.value(), flags, rargs,
+                                                            residual_args)) {
+          HelpRequested(_) => HelpRequested(Some(cmd_name)),
+          outcome => outcome,
+        },
+        (value, None) => {
+          residual_args.push(value);
+          Proceed
+        }
+      };
+      if outcome != Proceed {
+        return Ok(outcome);
+      }
+    }
+    Ok(Proceed)
+  }
+
+  // Look up a long option by name. With CtxFlags::AbbreviatedOptions, a
+  // prefix matching a single long name is accepted too.
+  fn find_long<'a>(&'a self, name: &str, flags: uint) -> Result<Option<&'a Opt>, ~str> {
+    match self.loptions.find_equiv(&name) {
+      Some(opt) => return Ok(Some(opt)),
+      None => if (flags & CtxFlags::AbbreviatedOptions) == 0 || name.len() == 0 {
+        return Ok(None);
+      }
+    }
+
+    // Several aliases of the same option aren't ambiguous, keep one name per option.
+    let mut matches: Vec<(&str, &'a Opt)> = Vec::new();
+    let mut prefixed: Vec<(&str, &'a Opt)> = self.loptions.iter()
+                                                 .map(|(lname, opt)| (lname.as_slice(), opt))
+                                                 .filter(|&(lname, _)| lname.starts_with(name))
+                                                 .collect();
+    prefixed.as_mut_slice().sort_by(|&(a, _), &(b, _)| a.cmp(&b));
+    for &(lname, opt) in prefixed.iter() {
+      if !matches.iter().any(|&(_, other)| other.id() == opt.id() && other.negated == opt.negated) {
+        matches.push((lname, opt));
+      }
+    }
+    match matches.len() {
+      0 => Ok(None),
+      1 => {
+        let &(_, opt) = matches.get(0);
+        Ok(Some(opt))
+      }
+      _ => {
+        let names: Vec<~str> = matches.iter().map(|&(lname, _)| format!("--{:s}", lname))
+                                      .collect();
+        Err(format!("Ambiguous option : {:s}, could be {:s}.", name,
+                    names.as_slice().connect(", ")))
+      }
+    }
+  }
+
+  // Build the error message for an unknown option, suggesting the
+  // closest long option when the name looks like a typo.
+  fn invalid_option(&self, name: ~str) -> ~str {
+    if name.char_len() > 1 {
+      match suggest(name.as_slice(), self.loptions.keys().map(|lname| lname.as_slice())) {
+        Some(lname) => return format!("Invalid option : {:s}. Did you mean --{:s}?",
+                                      name, lname),
+        None => {}
+      }
+    }
+    format!("Invalid option : {:s}.", name)
+  }
+
+  // Clear the results of every option. Hidden options aren't in print_options,
+  // so go through the maps.
+  fn reset(&self) {
+    for opt in self.loptions.values().chain(self.soptions.values()) {
+      *opt.result.borrow_mut() = Res::new();
+    }
+  }
+
+  // Add -h/--help for Context::enable_auto_help(), leaving out the names
+  // already used.
+  fn add_help(&mut self) {
+    let lname = if self.loptions.find_equiv(&"help").is_some() { None } else { Some("help") };
+    let sname = if self.soptions.contains_key(&'h') { None } else { Some('h') };
+    self.help = self.add_option(lname, sname, Some("Print this help"), Flags::Defaults).ok();
+  }
+
+  // What the parsing should do after opt was given: stop for the help and
+  // version options, go on for the others.
+  fn outcome_of(&self, opt: &Opt) -> Outcome {
+    let id = Some(opt.id());
+    if self.help.as_ref().map(|help| help.id()) == id {
+      HelpRequested(None)
+    } else if self.version.as_ref().map(|version| version.id()) == id {
+      VersionPrinted
+    } else {
+      Proceed
+    }
+  }
+
+  // Copy the results of the options, once per id whatever the number of names.
+  fn results(&self, command: Option<&str>) -> Vec<OptResult> {
+    let names = self.loptions.iter().map(|(lname, opt)| (Some(lname.as_slice()), None, opt))
+                    .chain(self.soptions.iter().map(|(sname, opt)| (None, Some(*sname), opt)));
+    let mut results: Vec<OptResult> = Vec::new();
+    for (lname, sname, opt) in names {
+      // The --no- variants share the results of their option
+      if opt.negated && opt.has_flag(Flags::Negatable) {
+        continue;
+      }
+      let id = opt.id();
+      let index = match results.iter().position(|result| result.id == id) {
+        Some(index) => index,
+        None => {
+          results.push(OptResult::new(id, command, opt));
+          results.len() - 1
+        }
+      };
+      let result = results.get_mut(index);
+      if !opt.negated {
+        // Not the decrementing option of a counter
+        result.name = opt.name();
+        result.secret = opt.has_flag(Flags::Secret);
+      }
+      lname.map(|name| result.long_names.push(name.to_owned()));
+      sname.map(|name| result.short_names.push(name));
+    }
+    for result in results.mut_iter() {
+      result.long_names.as_mut_slice().sort();
+      result.short_names.as_mut_slice().sort();
+    }
+    results
+  }
+
+  fn add_option<L: IntoMaybeOwned<'static>, D: IntoMaybeOwned<'static>>(
+                &mut self, long_name: Option<L>, short_name: Option<char>,
+                description: Option<D>, flags: uint) -> Result<Opt, &'static str> {
+
+    let opt = Opt::new(long_name, short_name, description, flags,
+                       Rc::new(RefCell::new(Res::new())));
+    self.register(opt)
+  }
+
+  fn add_typed_option<T: FromStr + Send, L: IntoMaybeOwned<'static>, D: IntoMaybeOwned<'static>>(
+                      &mut self, long_name: Option<L>, short_name: Option<char>,
+                      description: Option<D>, flags: uint)
+                      -> Result<TypedOpt<T>, &'static str> {
+    let flags = if (flags & (Flags::TakesArg | Flags::TakesOptionalArg)) == 0 {
+      flags | Flags::TakesArg
+    } else {
+      flags
+    };
+    let opt = try!(self.add_option(long_name, short_name, description, flags));
+    opt.conf.borrow_mut().parser = Some(Parser(parse_any::<T>));
+    Ok(TypedOpt { opt: opt, marker: marker::CovariantType })
+  }
+
+  fn add_map_option<L: IntoMaybeOwned<'static>, D: IntoMaybeOwned<'static>>(
+                    &mut self, long_name: Option<L>, short_name: Option<char>,
+                    description: Option<D>, duplicates: DuplicateKeys)
+                    -> Result<Opt, &'static str> {
+    let opt = try!(self.add_option(long_name, short_name, description, Flags::TakesArg));
+    opt.conf.borrow_mut().map = Some(duplicates);
+    Ok(opt)
+  }
+
+  fn add_counter<L: IntoMaybeOwned<'static>, D: IntoMaybeOwned<'static>>(
+                 &mut self, long_name: Option<L>, short_name: Option<char>,
+                 description: Option<D>, max: uint) -> Result<Opt, &'static str> {
+    let opt = try!(self.add_option(long_name, short_name, description, Flags::Defaults));
+    opt.conf.borrow_mut().max_level = Some(max);
+    Ok(opt)
+  }
+
+  fn add_decrement<L: IntoMaybeOwned<'static>, D: IntoMaybeOwned<'static>>(
+                   &mut self, counter: &Opt, long_name: Option<L>, short_name: Option<char>,
+                   description: Option<D>) -> Result<Opt, &'static str> {
+    if counter.conf.borrow().max_level.is_none() {
+      return Err("Only a counter can have a decrementing option");
+    }
+    // The decrementing option shares the results, the id and the maximum
+    // level of the counter, but has its own aliases.
+    let mut opt = Opt::new(long_name, short_name, description, Flags::Defaults,
+                           counter.result.clone());
+    {
+      let (counter_conf, mut conf) = (counter.conf.borrow(), opt.conf.borrow_mut());
+      conf.max_level = counter_conf.max_level;
+      conf.id = counter_conf.id;
+    }
+    opt.negated = true;
+    self.register(opt)
+  }
+
+  // Add opt to the maps of valid options, under its long and short names.
+  fn register(&mut self, opt: Opt) -> Result<Opt, &'static str> {
+    let (long_name, short_name) = (opt.long_name.clone(), opt.short_name);
+    // Check every name first, so that nothing is added on error
+    match long_name {
+      Some(ref name) => try!(self.check_long_name(&opt, name.as_slice())),
       None => if short_name.is_none() {
         return Err("An option needs either a short or a long name")
+      } else if opt.has_flag(Flags::Negatable) {
+        return Err("A negatable option needs a long name")
       }
     }
+    if short_name.map_or(false, |name| self.soptions.contains_key(&name)) {
+      return Err("An option with the same short name was already added");
+    }
 
+    self.update_alignment(&opt);
+    match long_name {
+      Some(name) => {
+        self.loptions.insert(name.as_slice().to_owned(), opt.clone());
+        if opt.has_flag(Flags::Negatable) {
+          self.add_negated(&opt, name.as_slice());
+        }
+      }
+      None => {}
+    }
     match short_name {
-      Some(name) => if !self.soptions.insert(name, opt.clone()) {
-        return Err("An option with the same short name was already added");
-      },
+      Some(name) => { self.soptions.insert(name, opt.clone()); }
       None => {}
     }
 
@@ -397,6 +1664,68 @@ impl LocalContext {
     Ok(opt)
   }
 
+  fn add_alias<L: IntoMaybeOwned<'static>>(&mut self, opt: &Opt, long_name: Option<L>,
+                                           short_name: Option<char>,
+                                           flags: uint) -> Result<(), &'static str> {
+    let long_name = long_name.map(|name| name.into_maybe_owned());
+    if long_name.is_none() && short_name.is_none() {
+      return Err("An alias needs either a short or a long name");
+    }
+    match long_name {
+      Some(ref name) => try!(self.check_long_name(opt, name.as_slice())),
+      None => {}
+    }
+    if short_name.map_or(false, |name| self.soptions.contains_key(&name)) {
+      return Err("An option with the same short name was already added");
+    }
+
+    let hidden = (flags & Flags::Hidden) != 0;
+    match long_name {
+      Some(name) => {
+        self.loptions.insert(name.as_slice().to_owned(), opt.clone());
+        if opt.has_flag(Flags::Negatable) {
+          self.add_negated(opt, name.as_slice());
+        }
+        if !hidden { opt.conf.borrow_mut().long_aliases.push(name); }
+      }
+      None => {}
+    }
+    short_name.map(|name| {
+      self.soptions.insert(name, opt.clone());
+      if !hidden { opt.conf.borrow_mut().short_aliases.push(name); }
+    });
+    self.update_alignment(opt);
+    Ok(())
+  }
+
+  // Check that name, and its --no- variant if opt is negatable, are free.
+  fn check_long_name(&self, opt: &Opt, name: &str) -> Result<(), &'static str> {
+    if self.loptions.find_equiv(&name).is_some() ||
+       (opt.has_flag(Flags::Negatable) &&
+        self.loptions.contains_key(&format!("no-{:s}", name))) {
+      return Err("An option with the same long name was already added");
+    }
+    Ok(())
+  }
+
+  // Register the --no-<name> variant of a negatable option, once its
+  // names were checked with check_long_name().
+  fn add_negated(&mut self, opt: &Opt, name: &str) {
+    let mut negated = opt.clone();
+    negated.negated = true;
+    self.loptions.insert(format!("no-{:s}", name), negated);
+  }
+
+  // The alignment is used in print_help() to make sure the columns are aligned.
+  fn update_alignment(&mut self, opt: &Opt) {
+    let conf = opt.conf.borrow();
+    let negatable = if opt.has_flag(Flags::Negatable) { 5 } else { 0 };
+    let len = negatable + opt.long_name.as_ref().map_or(0, |name| name.as_slice().len())
+            + conf.long_aliases.iter().fold(0, |len, name| len + name.as_slice().len() + 4)
+            + conf.short_aliases.len() * 4;
+    self.alignment = ::std::cmp::max(self.alignment, len + min_align);
+  }
+
   fn print_opt(&self, opt: &Opt, tab: &str) {
     // Not using tabs cause they mess with the alignment
     print!("{}", tab);
@@ -420,9 +1749,14 @@ impl LocalContext {
     }
     // print! until the description
     match opt.long_name {
-      Some(value) => {
-        align -= value.len();
-        print!("--{:s}", value);
+      Some(ref value) => {
+        align -= value.as_slice().len();
+        if opt.has_flag(Flags::Negatable) {
+          print!("--[no-]{:s}", value.as_slice());
+          align -= 5;
+        } else {
+          print!("--{:s}", value.as_slice());
+        }
         if opt.has_flag(Flags::TakesOptionalArg) {
           print!("[=argument]");
           align -= 11;
@@ -433,100 +1767,409 @@ impl LocalContext {
       }
       None => {}
     }
+    // print! the visible aliases
+    let conf = opt.conf.borrow();
+    let aliases = conf.short_aliases.iter().map(|name| format!("-{:c}", *name))
+                      .chain(conf.long_aliases.iter().map(|name| {
+                        format!("--{:s}", name.as_slice())
+                      }));
+    for (i, alias) in aliases.enumerate() {
+      let sep = if i == 0 && opt.long_name.is_none() { "" } else { ", " };
+      align -= sep.len() + alias.len();
+      print!("{:s}{:s}", sep, alias);
+    }
     print!("{:s}  ", " ".repeat(align));
     // print! until the end
     match opt.description {
-      Some(value) => println!("{}", value),
+      Some(ref value) => println!("{:s}", value.as_slice()),
       None => print!("\n")
     }
   }
 }
 
 impl Cmd {
-  fn new(description: &'static str) -> Cmd {
+  fn new<D: IntoMaybeOwned<'static>>(description: D) -> Cmd {
     Cmd { inner_ctx: LocalContext::new(description),
-          result: CmdRes(Rc::new(RefCell::new(false))) }
+          result: CmdRes(Rc::new(RefCell::new(false))),
+          aliases: Vec::new() }
   }
 
-  fn validate(&mut self, cmd_name: ~str, rargs: &mut Vec<RawArg>,
-              residual_args: &mut Vec<~str>) -> Result<(), ~str> {
-    // First check that the command has only been given once
-    if residual_args.len() != 0 {
-      Err(format!("Unexpected argument : {:s}.", residual_args.shift().unwrap()))
-    } else if self.result.check() {
+  fn validate(&mut self, cmd_name: ~str, flags: uint, rargs: &mut Vec<RawArg>,
+              residual_args: &mut Vec<RawArg>) -> Result<Outcome, ~str> {
+    // Check that the command has only been given once
+    if self.result.check() {
       Err(format!("Unexpected command : {:s}", cmd_name))
     } else {
       self.result.set();
-      self.inner_ctx.parse(&mut HashMap::new(), rargs, residual_args)
+      self.inner_ctx.parse(flags, &mut HashMap::new(), &HashMap::new(), rargs, residual_args)
     }
   }
 }
 
 impl Opt {
-  fn new(long_name: Option<&'static str>,
-         short_name: Option<char>,
-         descr: Option<&'static str>,
-         flags: uint,
-         result: Rc<RefCell<Res>>) -> Opt {
-
-    Opt { long_name: long_name, short_name: short_name, description: descr,
-          flags: flags, result: result }
+  fn new<L: IntoMaybeOwned<'static>, D: IntoMaybeOwned<'static>>(long_name: Option<L>,
+                                                                  short_name: Option<char>,
+                                                                  descr: Option<D>,
+                                                                  flags: uint,
+                                                                  result: Rc<RefCell<Res>>)
+                                                                  -> Opt {
+    Opt { long_name: long_name.map(|name| name.into_maybe_owned()), short_name: short_name,
+          description: descr.map(|descr| descr.into_maybe_owned()),
+          flags: flags, negated: false, result: result,
+          conf: Rc::new(RefCell::new(OptConf { validators: Vec::new(),
+                                               parser: None,
+                                               long_aliases: Vec::new(),
+                                               short_aliases: Vec::new(),
+                                               max_level: None,
+                                               arity: None,
+                                               delimiter: None,
+                                               map: None,
+                                               key_validators: Vec::new(),
+                                               id: unsafe {
+                                                 next_opt_id.fetch_add(1, SeqCst)
+                                               } })) }
   }
 
   fn has_flag(&self, flags: uint) -> bool {
     (self.flags & flags) != 0
   }
 
-  fn validate(&self, opt_name: ~str, rargs: &mut Vec<RawArg>,
-              residual_args: &mut Vec<~str>) -> Result<(), ~str> {
+  /// Return the id of the option, which identifies its results in a
+  /// ParseResult. Aliases share the id of their option.
+  pub fn id(&self) -> uint {
+    self.conf.borrow().id
+  }
 
+  // Name of the option as it would be given on the command line.
+  fn name(&self) -> ~str {
+    match (&self.long_name, self.short_name) {
+      (&Some(ref name), _) => format!("--{:s}", name.as_slice()),
+      (&None, Some(name)) => format!("-{:c}", name),
+      (&None, None) => ~"",
+    }
+  }
+
+  // word is the index of the group of short options the option was given in.
+  fn validate(&self, opt_name: ~str, position: uint, word: Option<uint>,
+              rargs: &mut Vec<RawArg>) -> Result<(), ~str> {
     let mut res = self.result.borrow_mut();
     res.passed += 1;
-    if residual_args.len() != 0 {
-      return Err(format!("Unexpected argument : {:s}.", residual_args.shift().unwrap()))
-    } else if res.passed > 1 && self.has_flag(Flags::Unique) {
+    if self.has_flag(Flags::Negatable) {
+      res.enabled = Some(!self.negated);
+    }
+
+    match self.conf.borrow().max_level {
+      Some(max) => {
+        let (max, sign) = (max as int, if self.negated { -1 } else { 1 });
+        let explicit = match rargs.last() { Some(&Attached(..)) => true, _ => false };
+        res.level = if explicit {
+          let value = rargs.pop().unwrap().value();
+          match from_str::<int>(value.as_slice()) {
+            Some(level) if level >= 0 && level <= max => sign * level,
+            _ => return Err(format!("Invalid level for option {:s} : {:s}, expected a number \
+                                     between 0 and {:d}", opt_name, value, max)),
+          }
+        } else {
+          ::std::cmp::max(-max, ::std::cmp::min(max, res.level + sign))
+        };
+        return Ok(());
+      }
+      None => {}
+    }
+
+    if res.passed > 1 && self.has_flag(Flags::Unique) {
       return Err(format!("The option : {:s} was given more than once", opt_name));
-    } else if self.has_flag(Flags::TakesArg | Flags::TakesOptionalArg) {
-      if rargs.last().map_or(false, |narg| !narg.option()) {
-        Some(rargs.pop().unwrap().value())
-      } else if self.has_flag(Flags::TakesArg) {
-        return Err(format!("Missing argument for option : {:s}", opt_name));
+    }
+
+    // Minimum and maximum number of values for this occurrence
+    let (min, max) = if self.negated {
+      (0, 0)
+    } else {
+      match self.conf.borrow().arity {
+        Some(Exactly(n)) => (n, n),
+        Some(Between(min, max)) => (min, max),
+        Some(AtLeast(min)) => (min, uint::MAX),
+        None if self.has_flag(Flags::TakesArg) => (1, 1),
+        None if self.has_flag(Flags::TakesOptionalArg) => (0, 1),
+        None => (0, 0),
+      }
+    };
+
+    let map = self.conf.borrow().map;
+    if map.is_some() {
+      // Map options take the rest of a group of short options as their
+      // value, eg -Dname=value.
+      let mut attached = StrBuf::new();
+      while rargs.last().map_or(false, |arg| match *arg {
+        Short(_, _, next) => Some(next) == word, _ => false
+      }) {
+        attached.push_str(rargs.pop().unwrap().value().as_slice());
+      }
+      if attached.len() > 0 {
+        rargs.push(Attached(attached.into_owned().into_bytes(), position));
+      }
+    }
+
+    let mut args = Vec::new();
+    while args.len() < max && rargs.last().map_or(false, |narg| !narg.option()) {
+      args.push(rargs.pop().unwrap());
+    }
+    if args.len() < min {
+      return Err(if min == 1 {
+        format!("Missing argument for option : {:s}", opt_name)
       } else {
-        None
+        format!("Missing argument for option : {:s}, expected {:u} values but got {:u}",
+                opt_name, min, args.len())
+      });
+    }
+
+    let delimiter = self.conf.borrow().delimiter;
+    let mut count = 0;
+    for arg in args.move_iter() {
+      let position = arg.position();
+      let bytes = arg.bytes();
+      let values = match delimiter {
+        Some(delimiter) => split_escaped(bytes.as_slice(), delimiter),
+        None => Vec::from_elem(1, bytes),
+      };
+
+      for bytes in values.move_iter() {
+        let value = lossy(bytes.as_slice());
+        {
+          // The validators of map options only check the value of the entry.
+          let checked = match map {
+            Some(duplicates) => try!(self.check_entry(&*res, opt_name.as_slice(),
+                                                      value.as_slice(), duplicates)),
+            None => value.as_slice(),
+          };
+          for validator in self.conf.borrow().validators.iter() {
+            match validator.check(checked) {
+              Err(msg) => return Err(format!("Invalid value '{:s}' for option {:s} : {:s}",
+                                             value, opt_name, msg)),
+              Ok(()) => {}
+            }
+          }
+          match self.conf.borrow().parser {
+            Some(Parser(parse)) => match parse(checked) {
+              Some(parsed) => res.parsed.push(parsed),
+              None => return Err(format!("Invalid value '{:s}' for option {:s} : {:s}", value,
+                                         opt_name, "the value doesn't have the expected type")),
+            },
+            None => {}
+          }
+        }
+        res.values.push(value);
+        res.raw_values.push(bytes);
+        res.positions.push(position);
+        count += 1;
       }
-    } else {
-      None
-    }.map(|value| res.values.push(value));
+    }
+    res.groups.push(count);
 
     Ok(())
   }
 
+  // Check a key=value entry given to a map option, and return its value.
+  fn check_entry<'a>(&self, res: &Res, opt_name: &str, entry: &'a str,
+                     duplicates: DuplicateKeys) -> Result<&'a str, ~str> {
+    let (key, value) = match split_entry(entry) {
+      Some((key, value)) if key.len() > 0 => (key, value),
+      _ => return Err(format!("Invalid value '{:s}' for option {:s} : expected key=value",
+                              entry, opt_name)),
+    };
+
+    for validator in self.conf.borrow().key_validators.iter() {
+      match validator.check(key) {
+        Err(msg) => return Err(format!("Invalid key '{:s}' for option {:s} : {:s}",
+                                       key, opt_name, msg)),
+        Ok(()) => {}
+      }
+    }
+
+    if duplicates == RejectDuplicates && res.values.iter().any(|previous| {
+      split_entry(previous.as_slice()).map_or(false, |(pkey, _)| pkey == key)
+    }) {
+      return Err(format!("The key {:s} was given more than once to option {:s}",
+                         key, opt_name));
+    }
+    Ok(value)
+  }
+
+  /// Add a check to run on every key given to a map option.
+  /// See add_validator().
+  pub fn add_key_validator<V: Validator + Send>(&self, validator: V) {
+    self.conf.borrow_mut().key_validators.push(~validator as ~Validator);
+  }
+
+  /// Split each argument given to the option at the delimiter, so that
+  /// --tags=a,b --tags c gives the values a, b and c. A delimiter preceded
+  /// by a backslash is kept in the value, as is a backslash preceded by
+  /// another backslash.
+  pub fn set_delimiter(&self, delimiter: char) {
+    self.conf.borrow_mut().delimiter = Some(delimiter);
+  }
+
+  /// Change the number of values the option takes each time it is given,
+  /// instead of the one or zero values set by Flags::TakesArg and
+  /// Flags::TakesOptionalArg. The values end at the next option.
+  pub fn set_arity(&self, arity: Arity) {
+    self.conf.borrow_mut().arity = Some(arity);
+  }
+
+  /// Add a check to run on every value given to this option. The checks are
+  /// run during Context::validate(), which returns Err() with the validator's
+  /// message if a value is rejected.
+  pub fn add_validator<V: Validator + Send>(&self, validator: V) {
+    self.conf.borrow_mut().validators.push(~validator as ~Validator);
+  }
+
   /// Return whether the option was given among the input arguments.
   pub fn check(&self) -> bool {
     self.count() != 0
   }
 
-  /// Return the value passed with the given option, or a default if
-  /// there was no value. print! a error message and the help if the value
-  /// was of an invalid type.
-  pub fn value_or<T: FromStr>(&self, ctx: &Context, default: T) -> T {
-    let mut res = self.result.borrow_mut();
-    match res.values.as_slice().head() {
-      Some(value) => match from_str(*value) {
-        Some(tvalue) => tvalue,
-        None => {
-          let msg = format!("Invalid type for value '{:s}'", *value);
-          ctx.print_help(Some(msg.as_slice()));
-          fail!();
-        }
+  /// For options added with Flags::Negatable, return Some(true) if the last
+  /// occurrence was --<name>, Some(false) if it was --no-<name>, and None if
+  /// the option wasn't given. Always None for other options.
+  pub fn enabled(&self) -> Option<bool> {
+    self.result.borrow().enabled
+  }
+
+  /// For counters, return the level reached: the number of times the counter
+  /// was given, minus the number of times its decrementing option was given,
+  /// bounded by its maximum. The last explicit level given replaces the count
+  /// so far, eg "-vv --verbose=1 -v" gives 2.
+  pub fn level(&self) -> int {
+    self.result.borrow().level
+  }
+
+  /// Return the value of the option, ie the last value it was given.
+  /// Return None if there is no value or if it isn't a valid T.
+  /// Unlike take_value(), the value is left in place, so several calls
+  /// give the same answer.
+  pub fn value<T: FromStr>(&self) -> Option<T> {
+    self.last_value()
+  }
+
+  /// Return the first value given to the option. See value().
+  pub fn first_value<T: FromStr>(&self) -> Option<T> {
+    self.result.borrow().values.as_slice().head().and_then(|value| from_str(value.as_slice()))
+  }
+
+  /// Return the last value given to the option. Same as value().
+  pub fn last_value<T: FromStr>(&self) -> Option<T> {
+    self.result.borrow().values.last().and_then(|value| from_str(value.as_slice()))
+  }
+
+  /// Return the bytes of the last value given to the option. Unlike the
+  /// strings converted by value(), they don't have to be valid UTF-8.
+  pub fn raw_value(&self) -> Option<~[u8]> {
+    self.result.borrow().raw_values.last().map(|bytes| bytes.clone())
+  }
+
+  /// Return the bytes of every value given to the option, in order.
+  pub fn raw_values(&self) -> Vec<~[u8]> {
+    self.result.borrow().raw_values.clone()
+  }
+
+  /// Return the last value given to the option as a path, which doesn't
+  /// have to be valid UTF-8.
+  pub fn path_value(&self) -> Option<Path> {
+    self.raw_value().map(|bytes| Path::new(bytes))
+  }
+
+  /// Iterate over the values given to the option, in order, with None
+  /// for the values which aren't valid T. The values are left in place.
+  pub fn values<T: FromStr>(&self) -> MoveItems<Option<T>> {
+    let values: Vec<Option<T>> = self.result.borrow().values.iter()
+                                     .map(|value| from_str(value.as_slice())).collect();
+    values.move_iter()
+  }
+
+  /// Return the value of the option (see value()), or a default if
+  /// there was no value. Return Err() if the value was of an invalid type.
+  pub fn try_value_or<T: FromStr>(&self, default: T) -> Result<T, ValueError> {
+    let res = self.result.borrow();
+    match res.values.last() {
+      Some(value) => match from_str(value.as_slice()) {
+        Some(tvalue) => Ok(tvalue),
+        None => Err(ValueError { option: self.name(), value: value.clone(),
+                                 position: *res.positions.last().unwrap() }),
       },
-      None => {
-        default
+      None => Ok(default),
+    }
+  }
+
+  /// For options added with add_map_option(), return the entries given to
+  /// the option, with their values converted into T. Return Err() for the
+  /// first value which isn't a valid T.
+  pub fn map_values<T: FromStr>(&self) -> Result<HashMap<~str, T>, ValueError> {
+    let res = self.result.borrow();
+    let mut map = HashMap::new();
+    for (entry, position) in res.values.iter().zip(res.positions.iter()) {
+      // The entries were checked in validate()
+      let (key, value) = match split_entry(entry.as_slice()) {
+        Some(key_value) => key_value,
+        None => continue,
+      };
+      match from_str(value) {
+        Some(tvalue) => { map.insert(key.to_owned(), tvalue); }
+        None => return Err(ValueError { option: self.name(), value: value.to_owned(),
+                                        position: *position }),
+      }
+    }
+    Ok(map)
+  }
+
+  /// Return the values given to the option, grouped by occurrence, eg
+  /// "--point 1 2 --point 3 4" gives [[1, 2], [3, 4]]. The values which
+  /// aren't valid T are None.
+  pub fn grouped_values<T: FromStr>(&self) -> Vec<Vec<Option<T>>> {
+    let res = self.result.borrow();
+    let mut values = res.values.iter();
+    res.groups.iter().map(|len| {
+      values.by_ref().take(*len).map(|value| from_str(value.as_slice())).collect()
+    }).collect()
+  }
+
+  /// Return every value given to the option, with its position in the input
+  /// arguments and the result of its conversion into a T.
+  pub fn parsed_values<T: FromStr>(&self) -> Vec<ParsedValue<T>> {
+    let res = self.result.borrow();
+    res.values.iter().zip(res.positions.iter()).map(|(value, position)| {
+      ParsedValue { raw: value.clone(), position: *position, value: from_str(value.as_slice()) }
+    }).collect()
+  }
+
+  /// Variant of values() which either converts all the values, or returns
+  /// Err() for the first one which isn't a valid T.
+  pub fn try_values<T: FromStr>(&self) -> Result<Vec<T>, ValueError> {
+    let mut values = Vec::new();
+    for parsed in self.parsed_values::<T>().move_iter() {
+      match parsed.value {
+        Some(value) => values.push(value),
+        None => return Err(ValueError { option: self.name(), value: parsed.raw,
+                                        position: parsed.position }),
+      }
+    }
+    Ok(values)
+  }
+
+  /// Return the value of the option (see value()), or a default if
+  /// there was no value. print! a error message and the help, then fail!()
+  /// if the value was of an invalid type.
+  pub fn value_or<T: FromStr>(&self, ctx: &Context, default: T) -> T {
+    match self.try_value_or(default) {
+      Ok(value) => value,
+      Err(err) => {
+        ctx.print_help(Some(format!("{}", err).as_slice()));
+        fail!();
       }
     }
   }
 
-  /// Returns the value attached with the given option. (ie --option=value).
+  /// Removes and returns the last value attached with the given option.
+  /// (ie --option=value). Kept for backwards compatibility, prefer value().
   /// If the value is cannot be parsed into a valid T, returns Ok(None).
   /// If the option was given with no value returns Right(true),
   /// or Right(false) if the option wasn't given.
@@ -535,7 +2178,18 @@ impl Opt {
     let passed = res.passed;
     match res.values.pop() {
       // Is there a way to avoid allocation of a new string when T: Str ?
-      Some(value) => Ok(from_str(value)),
+      Some(value) => {
+        res.positions.pop();
+        res.raw_values.pop();
+        let len = res.values.len();
+        res.parsed.truncate(len);
+        // The value belongs to the last occurrence which was given values
+        match res.groups.mut_iter().rev().find(|len| **len > 0) {
+          Some(len) => *len -= 1,
+          None => {}
+        }
+        Ok(from_str(value))
+      }
       None => if passed == 0 {
         Err(false)
       } else {
@@ -551,7 +2205,8 @@ impl Opt {
   }
 
   /// Variant of take_value() for when the option can receive several values.
-  /// eg --output=file1 --output=pipe1
+  /// eg --output=file1 --output=pipe1. The values aren't removed.
+  /// Kept for backwards compatibility, prefer values().
   pub fn take_values<T: FromStr>(&self) -> Result<~[Option<T>], uint> {
     let mut res = self.result.borrow_mut();
     if res.values.len() == 0 {
@@ -562,6 +2217,40 @@ impl Opt {
   }
 }
 
+impl<T: FromStr + Send + Clone> TypedOpt<T> {
+  /// Return the last value given to the option, or None if it wasn't given
+  /// any value.
+  pub fn get(&self) -> Option<T> {
+    // The values were converted once in validate().
+    let res = self.opt.result.borrow();
+    let value = res.parsed.last().and_then(|value| value.as_ref::<T>()).map(|value| value.clone());
+    value
+  }
+
+  /// Variant of get() returning a default if the option wasn't given any value.
+  pub fn get_or(&self, default: T) -> T {
+    self.get().unwrap_or(default)
+  }
+
+  /// Return all the values given to the option, in order.
+  pub fn get_all(&self) -> Vec<T> {
+    let res = self.opt.result.borrow();
+    let values = res.parsed.iter().filter_map(|value| value.as_ref::<T>())
+                           .map(|value| value.clone()).collect();
+    values
+  }
+
+  /// Return whether the option was given among the input arguments.
+  pub fn check(&self) -> bool {
+    self.opt.check()
+  }
+
+  /// Return the underlying untyped option.
+  pub fn opt<'a>(&'a self) -> &'a Opt {
+    &self.opt
+  }
+}
+
 impl CmdRes {
   pub fn check(&self) -> bool {
     match *self {
@@ -580,6 +2269,131 @@ impl CmdRes {
       }
     }
   }
+
+  fn reset(&self) {
+    match *self {
+      CmdRes(ref res) => {
+        let mut tmp = res.borrow_mut();
+        *tmp = false;
+      }
+    }
+  }
+}
+
+impl ParseResult {
+  /// Return the results of every option, in the order they were added.
+  pub fn options<'a>(&'a self) -> &'a [OptResult] {
+    self.options.as_slice()
+  }
+
+  /// Return the results of the option with the given id.
+  pub fn find_id<'a>(&'a self, id: uint) -> Option<&'a OptResult> {
+    self.options.iter().find(|opt| opt.id == id)
+  }
+
+  /// Return the results of the global option with the given long name or,
+  /// failing that, with the given short name.
+  pub fn find_option<'a>(&'a self, lname: Option<&str>,
+                         sname: Option<char>) -> Option<&'a OptResult> {
+    self.find_in(None, lname, sname)
+  }
+
+  /// Variant of find_option() for the options of a command.
+  pub fn find_command_option<'a>(&'a self, command: &str, lname: Option<&str>,
+                                 sname: Option<char>) -> Option<&'a OptResult> {
+    self.find_in(Some(command), lname, sname)
+  }
+
+  fn find_in<'a>(&'a self, command: Option<&str>, lname: Option<&str>,
+                 sname: Option<char>) -> Option<&'a OptResult> {
+    lname.and_then(|name| self.options.iter().find(|opt| {
+      opt.in_command(command) && opt.long_names.iter().any(|lname| lname.as_slice() == name)
+    })).or_else(|| sname.and_then(|name| self.options.iter().find(|opt| {
+      opt.in_command(command) && opt.short_names.contains(&name)
+    })))
+  }
+
+  /// Return the name of the command given, if any.
+  pub fn command<'a>(&'a self) -> Option<&'a str> {
+    self.command.as_ref().map(|name| name.as_slice())
+  }
+
+  /// Return the residual arguments, see Context::get_args().
+  pub fn args<'a>(&'a self) -> &'a [~str] {
+    self.args.as_slice()
+  }
+
+  /// Return the bytes of the residual arguments.
+  pub fn raw_args<'a>(&'a self) -> &'a [~[u8]] {
+    self.raw_args.as_slice()
+  }
+}
+
+/// The JSON export lists the options given, with their counts and values,
+/// the command given and the residual arguments, eg
+/// {"args":["x"],"command":["run"],"options":[{"count":1,"name":"--out","values":["a"]}]}
+impl ToJson for ParseResult {
+  fn to_json(&self) -> Json {
+    let mut object = ~TreeMap::new();
+    let options = self.options.iter().filter(|opt| opt.check()).map(|opt| opt.to_json());
+    object.insert(~"options", json::List(options.collect()));
+    object.insert(~"command", json::List(self.command.iter().map(|name| name.to_json())
+                                             .collect()));
+    object.insert(~"args", json::List(self.args.iter().map(|arg| arg.to_json()).collect()));
+    json::Object(object)
+  }
+}
+
+/// The values of the options added with Flags::Secret are replaced by
+/// "<redacted>".
+impl ToJson for OptResult {
+  fn to_json(&self) -> Json {
+    let mut object = ~TreeMap::new();
+    object.insert(~"name", self.name.to_json());
+    self.command.as_ref().map(|name| object.insert(~"command", name.to_json()));
+    object.insert(~"count", self.count.to_json());
+    let values = self.values.iter().map(|value| {
+      if self.secret { json::String(~"<redacted>") } else { value.to_json() }
+    });
+    object.insert(~"values", json::List(values.collect()));
+    self.enabled.map(|enabled| object.insert(~"enabled", enabled.to_json()));
+    if self.level != 0 {
+      object.insert(~"level", self.level.to_json());
+    }
+    json::Object(object)
+  }
+}
+
+impl OptResult {
+  fn new(id: uint, command: Option<&str>, opt: &Opt) -> OptResult {
+    let res = opt.result.borrow();
+    OptResult { id: id, name: opt.name(), command: command.map(|name| name.to_owned()),
+                long_names: Vec::new(), short_names: Vec::new(), count: res.passed,
+                values: res.values.clone(), raw_values: res.raw_values.clone(),
+                positions: res.positions.clone(), enabled: res.enabled, level: res.level,
+                secret: opt.has_flag(Flags::Secret) }
+  }
+
+  fn in_command(&self, command: Option<&str>) -> bool {
+    self.command.as_ref().map(|name| name.as_slice()) == command
+  }
+
+  /// Return true if the option was given.
+  pub fn check(&self) -> bool {
+    self.count > 0
+  }
+
+  /// Return the last value given to the option, None if there is no value
+  /// or if it isn't a valid T.
+  pub fn value<T: FromStr>(&self) -> Option<T> {
+    self.values.last().and_then(|value| from_str(value.as_slice()))
+  }
+
+  /// Return every value given to the option, None for the values which
+  /// aren't valid T.
+  pub fn values<T: FromStr>(&self) -> Vec<Option<T>> {
+    self.values.iter().map(|value| from_str(value.as_slice())).collect()
+  }
 }
 
 impl OptGroup for Cmd { }
@@ -1,9 +1,20 @@
 #![allow(unused_must_use)]
 #![cfg(test)]
+#![feature(phase)]
 
+#[phase(syntax, link)]
 extern crate cmdparse;
-use cmdparse::{Context,OptGroup,Flags};
+extern crate serialize;
+use cmdparse::{Context,OptGroup,Flags,CtxFlags,InRange,TypedOpt,ValueError,ParsedValue};
+use cmdparse::{Exactly,Between,AtLeast,RejectDuplicates,LastWins};
+use cmdparse::{split_words,quote_word,join_words};
+use cmdparse::{Outcome,Proceed,HelpRequested};
+use serialize::json;
+use serialize::json::ToJson;
+use std::from_str::FromStr;
+use std::io::{ChanWriter, File, TempDir};
 use std::str;
+use std::sync::atomics::{AtomicUint, INIT_ATOMIC_UINT, SeqCst};
 
 // Tests for the options creation
 #[test]
@@ -702,3 +713,905 @@ fn test_command_option_with() {
 
   assert!(ctx.get_args().as_slice().head().unwrap() == &~"argument");
 }
+
+// Tests for the validators
+fn not_empty(value: &str) -> Result<(), ~str> {
+  if value.len() == 0 { Err(~"the value can't be empty") } else { Ok(()) }
+}
+
+#[test]
+fn test_validator_valid() {
+  let args = ~[~"test", ~"--port=8080", ~"-n", ~"name"];
+  let mut ctx = Context::new("test [option] [argument]", args);
+  let p_opt = ctx.add_option(Some("port"), Some('p'), None, Flags::TakesArg).unwrap();
+  let n_opt = ctx.add_option(None, Some('n'), None, Flags::TakesArg).unwrap();
+  p_opt.add_validator(InRange { min: 1, max: 65535 });
+  n_opt.add_validator(not_empty);
+  ctx.validate().map_err(|msg| { ctx.print_help(Some(msg.as_slice())); assert!(false);});
+  match p_opt.take_value::<int>() {
+    Ok(Some(val)) => assert!(val == 8080), _ => assert!(false),
+  }
+}
+
+#[test]
+fn test_validator_invalid() {
+  let args = ~[~"test", ~"-p", ~"0"];
+  let mut ctx = Context::new("test [option] [argument]", args);
+  let p_opt = ctx.add_option(Some("port"), Some('p'), None, Flags::TakesArg).unwrap();
+  p_opt.add_validator(InRange { min: 1, max: 65535 });
+  match ctx.validate() {
+    Err(msg) => ctx.print_help(Some(msg.as_slice())),
+    Ok(()) => assert!(false),
+  }
+}
+
+// Tests for the suggestions
+#[test]
+fn test_suggest_long_option() {
+  let args = ~[~"test", ~"--verbos"];
+  let mut ctx = Context::new("test [option] [argument]", args);
+  ctx.add_lopt("verbose", "Be verbose");
+  ctx.add_lopt("quiet", "Be quiet");
+  match ctx.validate() {
+    Err(msg) => assert!(msg.ends_with("Did you mean --verbose?")),
+    Ok(()) => assert!(false),
+  }
+}
+
+#[test]
+fn test_suggest_command() {
+  let args = ~[~"test", ~"-a", ~"comand", ~"-b"];
+  let mut ctx = Context::new("test [option] command [command-options]", args);
+  ctx.add_sopt('a', "Option a");
+  ctx.add_cmd_with("command", "description", |cmd| cmd.add_sopt('b', "Cmd option b"));
+  match ctx.validate() {
+    Err(msg) => assert!(msg.ends_with("Did you mean command?")),
+    Ok(()) => assert!(false),
+  }
+}
+
+#[test]
+fn test_suggest_command_alone() {
+  // Without CtxFlags::RejectCommandTypos, arguments close to a command name are accepted
+  let args = ~[~"test", ~"and"];
+  let mut ctx = Context::new("test [option] command [command-options]", args);
+  ctx.add_command("add", "description").unwrap();
+  ctx.validate().map_err(|msg| { ctx.print_help(Some(msg.as_slice())); assert!(false);});
+  assert!(ctx.get_args().as_slice() == &[~"and"]);
+
+  let args = ~[~"test", ~"comand"];
+  let mut ctx = Context::new("test [option] command [command-options]", args);
+  ctx.set_flags(CtxFlags::RejectCommandTypos);
+  ctx.add_cmd_with("command", "description", |cmd| cmd.add_sopt('b', "Cmd option b"));
+  match ctx.validate() {
+    Err(msg) => assert!(msg == ~"Unexpected argument : comand. Did you mean command?"),
+    Ok(()) => assert!(false),
+  }
+
+  // Arguments which aren't close to a command name are left alone
+  let args = ~[~"test", ~"file"];
+  let mut ctx = Context::new("test [option] command [command-options]", args);
+  ctx.set_flags(CtxFlags::RejectCommandTypos);
+  ctx.add_cmd_with("command", "description", |cmd| cmd.add_sopt('b', "Cmd option b"));
+  ctx.validate().map_err(|msg| { ctx.print_help(Some(msg.as_slice())); assert!(false);});
+  assert!(ctx.get_args().as_slice() == &[~"file"]);
+}
+
+// Tests for the abbreviated long options
+#[test]
+fn test_abbreviation_valid() {
+  let args = ~[~"test", ~"--verb", ~"--out=file"];
+  let mut ctx = Context::new("test [option] [argument]", args);
+  ctx.set_flags(CtxFlags::AbbreviatedOptions);
+  let verbose = ctx.add_lopt("verbose", "Be verbose");
+  let version = ctx.add_lopt("version", "Print the version");
+  let output = ctx.add_option(Some("output"), None, None, Flags::TakesArg).unwrap();
+  ctx.validate().map_err(|msg| { ctx.print_help(Some(msg.as_slice())); assert!(false);});
+  assert!(verbose.check());
+  assert!(!version.check());
+  match output.take_value::<~str>() {
+    Ok(Some(val)) => assert!(val == ~"file"), _ => assert!(false),
+  }
+}
+
+#[test]
+fn test_abbreviation_ambiguous() {
+  let args = ~[~"test", ~"--ver"];
+  let mut ctx = Context::new("test [option] [argument]", args);
+  ctx.set_flags(CtxFlags::AbbreviatedOptions);
+  ctx.add_lopt("verbose", "Be verbose");
+  ctx.add_lopt("version", "Print the version");
+  match ctx.validate() {
+    Err(msg) => assert!(msg == ~"Ambiguous option : ver, could be --verbose, --version."),
+    Ok(()) => assert!(false),
+  }
+}
+
+#[test]
+fn test_abbreviation_of_aliases() {
+  let args = ~[~"test", ~"--col"];
+  let mut ctx = Context::new("test [option] [argument]", args);
+  ctx.set_flags(CtxFlags::AbbreviatedOptions);
+  let color = ctx.add_lopt("color", "Use colors");
+  ctx.add_alias(&color, Some("colour"), None, Flags::Defaults).unwrap();
+  ctx.validate().map_err(|msg| { ctx.print_help(Some(msg.as_slice())); assert!(false);});
+  assert!(color.check());
+}
+
+#[test]
+fn test_abbreviation_disabled() {
+  let args = ~[~"test", ~"--verb"];
+  let mut ctx = Context::new("test [option] [argument]", args);
+  ctx.add_lopt("verbose", "Be verbose");
+  match ctx.validate() {
+    Err(msg) => ctx.print_help(Some(msg.as_slice())),
+    Ok(()) => assert!(false),
+  }
+}
+
+// Tests for the aliases
+#[test]
+fn test_option_alias() {
+  let args = ~[~"test", ~"--colour=never", ~"-C", ~"always"];
+  let mut ctx = Context::new("test [option] [argument]", args);
+  let color = ctx.add_option(Some("color"), Some('c'), None, Flags::TakesArg).unwrap();
+  ctx.add_alias(&color, Some("colour"), Some('C'), Flags::Hidden).unwrap();
+  ctx.add_alias(&color, Some("color"), None, Flags::Defaults).unwrap_err();
+  ctx.add_alias(&color, None, None, Flags::Defaults).unwrap_err();
+  ctx.validate().map_err(|msg| { ctx.print_help(Some(msg.as_slice())); assert!(false);});
+  assert!(color.count() == 2);
+  match color.take_values::<~str>() {
+    Ok(values) => assert!(values == ~[Some(~"never"), Some(~"always")]),
+    Err(_) => assert!(false),
+  }
+}
+
+#[test]
+fn test_command_alias() {
+  let args = ~[~"test", ~"rm", ~"-f"];
+  let mut ctx = Context::new("test [option] command [command-options]", args);
+  let (cmd_res, f_opt) = ctx.add_cmd_with("remove", "Remove a file", |cmd| {
+    cmd.add_sopt('f', "Force")
+  });
+  ctx.add_command_alias("remove", "rm", Flags::Defaults).unwrap();
+  ctx.add_command_alias("remove", "rm", Flags::Defaults).unwrap_err();
+  ctx.add_command_alias("delete", "del", Flags::Defaults).unwrap_err();
+  ctx.add_command("rm", "description").unwrap_err();
+  ctx.validate().map_err(|msg| { ctx.print_help(Some(msg.as_slice())); assert!(false);});
+  assert!(cmd_res.check());
+  assert!(f_opt.check());
+}
+
+// Tests for the negatable options
+#[test]
+fn test_negatable_last_wins() {
+  let args = ~[~"test", ~"--cache", ~"--no-cache", ~"--no-color", ~"--color"];
+  let mut ctx = Context::new("test [option] [argument]", args);
+  let cache = ctx.add_option(Some("cache"), None, None, Flags::Negatable).unwrap();
+  let color = ctx.add_option(Some("color"), None, None, Flags::Negatable).unwrap();
+  let sync = ctx.add_option(Some("sync"), Some('s'), None, Flags::Negatable).unwrap();
+  ctx.validate().map_err(|msg| { ctx.print_help(Some(msg.as_slice())); assert!(false);});
+  assert!(cache.enabled() == Some(false));
+  assert!(color.enabled() == Some(true));
+  assert!(sync.enabled() == None);
+}
+
+#[test]
+fn test_negatable_invalid() {
+  let mut ctx = Context::new("test [option] [argument]", ~[~"test", ~"--cache"]);
+  ctx.add_option(None, Some('c'), None, Flags::Negatable).unwrap_err();
+  ctx.add_lopt("no-cache", "Disable the cache");
+  ctx.add_option(Some("cache"), None, None, Flags::Negatable).unwrap_err();
+  // Nothing is added when the --no- variant is already used
+  ctx.validate().unwrap_err();
+  let toggle = ctx.add_option(Some("toggle"), None, None, Flags::Negatable).unwrap();
+  ctx.add_lopt("no-switch", "Not a switch");
+  ctx.add_alias(&toggle, Some("switch"), None, Flags::Defaults).unwrap_err();
+  assert!(ctx.find_option(Some("switch"), None).is_none());
+}
+
+// Tests for the counters
+#[test]
+fn test_counter_bounded() {
+  let args = ~[~"test", ~"-vvvv", ~"-q", ~"--verbose=1", ~"-v"];
+  let mut ctx = Context::new("test [option] [argument]", args);
+  let verbose = ctx.add_counter(Some("verbose"), Some('v'), None, 3).unwrap();
+  let quiet = ctx.add_decrement(&verbose, Some("quiet"), Some('q'), None).unwrap();
+  ctx.validate().map_err(|msg| { ctx.print_help(Some(msg.as_slice())); assert!(false);});
+  assert!(verbose.level() == 2);
+  assert!(quiet.level() == 2);
+}
+
+#[test]
+fn test_counter_decrement() {
+  let args = ~[~"test", ~"-qqq", ~"-v"];
+  let mut ctx = Context::new("test [option] [argument]", args);
+  let verbose = ctx.add_counter(Some("verbose"), Some('v'), None, 2).unwrap();
+  ctx.add_decrement(&verbose, None, Some('q'), None).unwrap();
+  ctx.validate().map_err(|msg| { ctx.print_help(Some(msg.as_slice())); assert!(false);});
+  assert!(verbose.level() == -1);
+}
+
+#[test]
+fn test_counter_invalid_level() {
+  let args = ~[~"test", ~"--verbose=4"];
+  let mut ctx = Context::new("test [option] [argument]", args);
+  let verbose = ctx.add_counter(Some("verbose"), Some('v'), None, 3).unwrap();
+  let other = ctx.add_sopt('o', "Not a counter");
+  ctx.add_decrement(&other, None, Some('q'), None).unwrap_err();
+  match ctx.validate() {
+    Err(msg) => ctx.print_help(Some(msg.as_slice())),
+    Ok(()) => assert!(false),
+  }
+  verbose.level();
+}
+
+// Tests for the typed options
+#[test]
+fn test_typed_option_valid() {
+  let args = ~[~"test", ~"-p", ~"80", ~"--port=8080", ~"--name", ~"host"];
+  let mut ctx = Context::new("test [option] [argument]", args);
+  let port: TypedOpt<u16> = ctx.add_typed_option(Some("port"), Some('p'), None,
+                                                  Flags::Defaults).unwrap();
+  let name: TypedOpt<~str> = ctx.add_typed_option(Some("name"), None, None,
+                                                   Flags::TakesArg).unwrap();
+  let ratio: TypedOpt<f32> = ctx.add_typed_option(Some("ratio"), None, None,
+                                                   Flags::TakesOptionalArg).unwrap();
+  ctx.validate().map_err(|msg| { ctx.print_help(Some(msg.as_slice())); assert!(false);});
+  assert!(port.get() == Some(8080));
+  assert!(port.get() == Some(8080));
+  assert!(port.get_all().as_slice() == &[80, 8080]);
+  assert!(name.get() == Some(~"host"));
+  assert!(!ratio.check());
+  assert!(ratio.get_or(0.5) == 0.5);
+}
+
+#[test]
+fn test_typed_option_invalid() {
+  let args = ~[~"test", ~"--port=http"];
+  let mut ctx = Context::new("test [option] [argument]", args);
+  let port: TypedOpt<u16> = ctx.add_typed_option(Some("port"), Some('p'), None,
+                                                  Flags::Defaults).unwrap();
+  match ctx.validate() {
+    Err(msg) => ctx.print_help(Some(msg.as_slice())),
+    Ok(()) => assert!(false),
+  }
+  assert!(port.get() == None);
+}
+
+static mut parse_count: AtomicUint = INIT_ATOMIC_UINT;
+
+#[deriving(Clone, Eq, Show)]
+struct Counted(int);
+
+impl FromStr for Counted {
+  fn from_str(value: &str) -> Option<Counted> {
+    unsafe { parse_count.fetch_add(1, SeqCst); }
+    from_str::<int>(value).map(|value| Counted(value))
+  }
+}
+
+#[test]
+fn test_typed_option_parsed_once() {
+  let args = ~[~"test", ~"--level=1", ~"--level=2"];
+  let mut ctx = Context::new("test [option] [argument]", args);
+  let level: TypedOpt<Counted> = ctx.add_typed_option(Some("level"), None, None,
+                                                       Flags::Defaults).unwrap();
+  ctx.validate().map_err(|msg| { ctx.print_help(Some(msg.as_slice())); assert!(false);});
+  let count = unsafe { parse_count.load(SeqCst) };
+  assert!(level.get() == Some(Counted(2)));
+  assert!(level.get_all().as_slice() == &[Counted(1), Counted(2)]);
+  assert!(unsafe { parse_count.load(SeqCst) } == count);
+  // Taking a value also removes its converted value
+  assert!(level.opt().take_value::<int>() == Ok(Some(2)));
+  assert!(level.get() == Some(Counted(1)));
+}
+
+// Tests for the options structs
+cmdparse_options!(struct Options {
+  verbose: uint = (Some("verbose"), Some('v'), Some("Be verbose"), Flags::Defaults),
+  dry_run: bool = (Some("dry-run"), None, Some("Do nothing"), Flags::Defaults),
+  jobs: Option<int> = (None, Some('j'), None, Flags::TakesArg),
+  output: Option<~str> = (Some("output"), Some('o'), None, Flags::TakesArg),
+  include: Vec<~str> = (None, Some('I'), None, Flags::TakesArg)
+})
+
+#[test]
+fn test_options_struct() {
+  let args = ~[~"test", ~"-vv", ~"-j", ~"4", ~"-I", ~"a", ~"-I", ~"b"];
+  let mut ctx = Context::new("test [option] [argument]", args);
+  Options::register(&mut ctx).unwrap();
+  ctx.validate().map_err(|msg| { ctx.print_help(Some(msg.as_slice())); assert!(false);});
+  let options = Options::from_context(&ctx);
+  assert!(options.verbose == 2);
+  assert!(!options.dry_run);
+  assert!(options.jobs == Some(4));
+  assert!(options.output == None);
+  assert!(options.include.as_slice() == &[~"a", ~"b"]);
+}
+
+#[test]
+fn test_options_struct_invalid_value() {
+  let args = ~[~"test", ~"-j", ~"abc"];
+  let mut ctx = Context::new("test [option] [argument]", args);
+  Options::register(&mut ctx).unwrap();
+  match ctx.validate() {
+    Err(msg) => assert!(msg == ~"Invalid value 'abc' for option j : \
+                                 the value doesn't have the expected type"),
+    Ok(()) => assert!(false),
+  }
+}
+
+// Tests for try_value_or
+#[test]
+fn test_try_value_or() {
+  let args = ~[~"test", ~"-b", ~"true", ~"--count=many"];
+  let mut ctx = Context::new("test [option] [argument]", args);
+  let b_opt = ctx.add_option(None, Some('b'), None, Flags::TakesArg).unwrap();
+  let c_opt = ctx.add_option(Some("count"), Some('c'), None, Flags::TakesArg).unwrap();
+  let d_opt = ctx.add_option(None, Some('d'), None, Flags::TakesArg).unwrap();
+  ctx.validate().map_err(|msg| { ctx.print_help(Some(msg.as_slice())); assert!(false);});
+  assert!(b_opt.try_value_or(false) == Ok(true));
+  assert!(d_opt.try_value_or(3) == Ok(3));
+  match c_opt.try_value_or(3) {
+    Ok(_) => assert!(false),
+    Err(ValueError { option, value, position }) => {
+      assert!(option == ~"--count");
+      assert!(value == ~"many");
+      assert!(position == 3);
+    }
+  }
+}
+
+// Tests for the non-destructive accessors
+#[test]
+fn test_value_last_wins() {
+  let args = ~[~"test", ~"-i", ~"33", ~"-i", ~"notanint", ~"--int=31"];
+  let mut ctx = Context::new("test [option] [argument]", args);
+  let e_opt = ctx.add_option(Some("int"), Some('i'), None, Flags::TakesArg).unwrap();
+  let f_opt = ctx.add_option(None, Some('f'), None, Flags::TakesArg).unwrap();
+  ctx.validate().map_err(|msg| { ctx.print_help(Some(msg.as_slice())); assert!(false);});
+  assert!(e_opt.value::<int>() == Some(31));
+  assert!(e_opt.value::<int>() == Some(31));
+  assert!(e_opt.first_value::<int>() == Some(33));
+  assert!(e_opt.last_value::<int>() == Some(31));
+  assert!(e_opt.value_or(&ctx, 0) == 31);
+  let values: ~[Option<int>] = e_opt.values().collect();
+  assert!(values == ~[Some(33), None, Some(31)]);
+  assert!(f_opt.value::<int>() == None);
+  assert!(f_opt.values::<int>().next() == None);
+}
+
+// Tests for the per-value errors
+#[test]
+fn test_parsed_values() {
+  let args = ~[~"test", ~"-i", ~"33" , ~"-i", ~"notanint", ~"--int=31"];
+  let mut ctx = Context::new("test [option] [argument]", args);
+  let e_opt = ctx.add_option(Some("int"), Some('i'), None, Flags::TakesArg).unwrap();
+  ctx.validate().map_err(|msg| { ctx.print_help(Some(msg.as_slice())); assert!(false);});
+  let values = e_opt.parsed_values::<int>();
+  assert!(values.as_slice() == &[
+    ParsedValue { raw: ~"33", position: 2, value: Some(33) },
+    ParsedValue { raw: ~"notanint", position: 4, value: None },
+    ParsedValue { raw: ~"31", position: 5, value: Some(31) }]);
+  match e_opt.try_values::<int>() {
+    Ok(_) => assert!(false),
+    Err(err) => assert!(err == ValueError { option: ~"--int", value: ~"notanint", position: 4 }),
+  }
+}
+
+#[test]
+fn test_try_values_valid() {
+  let args = ~[~"test", ~"-i", ~"33" , ~"--int=31"];
+  let mut ctx = Context::new("test [option] [argument]", args);
+  let e_opt = ctx.add_option(Some("int"), Some('i'), None, Flags::TakesArg).unwrap();
+  ctx.validate().map_err(|msg| { ctx.print_help(Some(msg.as_slice())); assert!(false);});
+  match e_opt.try_values::<int>() {
+    Ok(values) => assert!(values.as_slice() == &[33, 31]),
+    Err(_) => assert!(false),
+  }
+}
+
+// Tests for the options taking several values
+#[test]
+fn test_arity_valid() {
+  let args = ~[~"test", ~"--point", ~"1", ~"2", ~"--files", ~"a", ~"b", ~"c", ~"-r", ~"3",
+               ~"--point=3", ~"4", ~"argument"];
+  let mut ctx = Context::new("test [option] [argument]", args);
+  let point = ctx.add_option(Some("point"), None, None, Flags::Defaults).unwrap();
+  let files = ctx.add_option(Some("files"), None, None, Flags::Defaults).unwrap();
+  let range = ctx.add_option(None, Some('r'), None, Flags::Defaults).unwrap();
+  point.set_arity(Exactly(2));
+  files.set_arity(AtLeast(1));
+  range.set_arity(Between(1, 2));
+  ctx.validate().map_err(|msg| { ctx.print_help(Some(msg.as_slice())); assert!(false);});
+  let points = point.grouped_values::<int>();
+  assert!(points.len() == 2);
+  assert!(points.get(0).as_slice() == &[Some(1), Some(2)]);
+  assert!(points.get(1).as_slice() == &[Some(3), Some(4)]);
+  let files = files.grouped_values::<~str>();
+  assert!(files.len() == 1);
+  assert!(files.get(0).as_slice() == &[Some(~"a"), Some(~"b"), Some(~"c")]);
+  let ranges = range.grouped_values::<int>();
+  assert!(ranges.len() == 1);
+  assert!(ranges.get(0).as_slice() == &[Some(3)]);
+  assert!(ctx.get_args().as_slice() == &[~"argument"]);
+}
+
+#[test]
+fn test_take_value_keeps_results_in_sync() {
+  let args = ~[~"test", ~"--point", ~"1", ~"2", ~"--point", ~"3", ~"x"];
+  let mut ctx = Context::new("test [option] [argument]", args);
+  let point = ctx.add_option(Some("point"), None, None, Flags::Defaults).unwrap();
+  point.set_arity(Exactly(2));
+  ctx.validate().map_err(|msg| { ctx.print_help(Some(msg.as_slice())); assert!(false);});
+  assert!(point.take_value::<~str>() == Ok(Some(~"x")));
+  let points = point.grouped_values::<int>();
+  assert!(points.len() == 2);
+  assert!(points.get(1).as_slice() == &[Some(3)]);
+  assert!(point.raw_value() == Some("3".as_bytes().to_owned()));
+  let values = point.parsed_values::<int>();
+  assert!(values.len() == 3);
+  assert!(values.last().unwrap().position == 5);
+}
+
+#[test]
+fn test_arity_missing_values() {
+  let args = ~[~"test", ~"--point", ~"1", ~"-a"];
+  let mut ctx = Context::new("test [option] [argument]", args);
+  let point = ctx.add_option(Some("point"), None, None, Flags::Defaults).unwrap();
+  ctx.add_sopt('a', "Option a");
+  point.set_arity(Exactly(2));
+  match ctx.validate() {
+    Err(msg) => ctx.print_help(Some(msg.as_slice())),
+    Ok(()) => assert!(false),
+  }
+}
+
+// Tests for the delimited values
+#[test]
+fn test_delimiter() {
+  let args = ~[~"test", ~"--tags=a,b", ~"--tags", ~"c", ~"-t", ~"d\\,e,f\\\\,"];
+  let mut ctx = Context::new("test [option] [argument]", args);
+  let tags = ctx.add_option(Some("tags"), Some('t'), None, Flags::TakesArg).unwrap();
+  tags.set_delimiter(',');
+  ctx.validate().map_err(|msg| { ctx.print_help(Some(msg.as_slice())); assert!(false);});
+  let values: ~[Option<~str>] = tags.values().collect();
+  assert!(values == ~[Some(~"a"), Some(~"b"), Some(~"c"), Some(~"d,e"), Some(~"f\\"), Some(~"")]);
+  let groups = tags.grouped_values::<~str>();
+  assert!(groups.len() == 3);
+  assert!(groups.get(0).len() == 2);
+  assert!(groups.get(2).len() == 3);
+}
+
+// Tests for the map options
+fn identifier(key: &str) -> Result<(), ~str> {
+  if key.chars().all(|c| c.is_alphanumeric() || c == '_') {
+    Ok(())
+  } else {
+    Err(~"expected an identifier")
+  }
+}
+
+#[test]
+fn test_map_option_valid() {
+  let args = ~[~"test", ~"-Dfoo=bar", ~"-D", ~"baz=1", ~"--set", ~"foo=qux", ~"-vDempty="];
+  let mut ctx = Context::new("test [option] [argument]", args);
+  let define = ctx.add_map_option(Some("set"), Some('D'), None, LastWins).unwrap();
+  let v_opt = ctx.add_sopt('v', "Option v");
+  define.add_key_validator(identifier);
+  ctx.validate().map_err(|msg| { ctx.print_help(Some(msg.as_slice())); assert!(false);});
+  assert!(v_opt.check());
+  match define.map_values::<~str>() {
+    Ok(map) => {
+      assert!(map.len() == 3);
+      assert!(map.get(&~"foo") == &~"qux");
+      assert!(map.get(&~"baz") == &~"1");
+      assert!(map.get(&~"empty") == &~"");
+    }
+    Err(_) => assert!(false),
+  }
+  define.map_values::<int>().unwrap_err();
+}
+
+#[test]
+fn test_map_option_duplicate() {
+  let args = ~[~"test", ~"-Dfoo=bar", ~"-Dfoo=baz"];
+  let mut ctx = Context::new("test [option] [argument]", args);
+  ctx.add_map_option(None, Some('D'), None, RejectDuplicates).unwrap();
+  match ctx.validate() {
+    Err(msg) => ctx.print_help(Some(msg.as_slice())),
+    Ok(()) => assert!(false),
+  }
+}
+
+#[test]
+fn test_map_option_invalid_key() {
+  let args = ~[~"test", ~"-D", ~"foo bar=baz", ~"-D=1"];
+  let mut ctx = Context::new("test [option] [argument]", args);
+  let define = ctx.add_map_option(None, Some('D'), None, LastWins).unwrap();
+  define.add_key_validator(identifier);
+  match ctx.validate() {
+    Err(msg) => ctx.print_help(Some(msg.as_slice())),
+    Ok(()) => assert!(false),
+  }
+}
+
+// Tests for the response files
+fn write_response_file(dir: &TempDir, name: &str, content: &str) -> ~str {
+  let path = dir.path().join(name);
+  File::create(&path).write_str(content).unwrap();
+  path.as_str().unwrap().to_owned()
+}
+
+#[test]
+fn test_response_file_valid() {
+  let dir = TempDir::new("cmdparse").unwrap();
+  write_response_file(&dir, "nested.rsp", "--name 'a b'\n");
+  let main = write_response_file(&dir, "main.rsp", "-v \"quoted \\\"value\\\"\" @nested.rsp ''");
+  let args = ~[~"test", ~"-o", format!("@{:s}", main), ~"last"];
+  let mut ctx = Context::new("test [option] [argument]", args);
+  ctx.set_flags(CtxFlags::ResponseFiles);
+  let o_opt = ctx.add_sopt('o', "Option o");
+  let v_opt = ctx.add_option(None, Some('v'), None, Flags::TakesArg).unwrap();
+  let name = ctx.add_option(Some("name"), None, None, Flags::TakesArg).unwrap();
+  ctx.validate().map_err(|msg| { ctx.print_help(Some(msg.as_slice())); assert!(false);});
+  assert!(o_opt.check());
+  assert!(v_opt.value::<~str>() == Some(~"quoted \"value\""));
+  assert!(name.value::<~str>() == Some(~"a b"));
+  assert!(ctx.get_args().as_slice() == &[~"", ~"last"]);
+}
+
+#[test]
+fn test_response_file_short_groups() {
+  let dir = TempDir::new("cmdparse").unwrap();
+  let path = write_response_file(&dir, "groups.rsp", "-Dfoo=bar -v");
+  let args = ~[~"test", format!("@{:s}", path)];
+  let mut ctx = Context::new("test [option] [argument]", args);
+  ctx.set_flags(CtxFlags::ResponseFiles);
+  let defines = ctx.add_map_option(None, Some('D'), None, RejectDuplicates).unwrap();
+  let v_opt = ctx.add_sopt('v', "Option v");
+  ctx.validate().map_err(|msg| { ctx.print_help(Some(msg.as_slice())); assert!(false);});
+  assert!(defines.value::<~str>() == Some(~"foo=bar"));
+  assert!(v_opt.check());
+}
+
+#[test]
+fn test_response_file_cycle() {
+  let dir = TempDir::new("cmdparse").unwrap();
+  let path = write_response_file(&dir, "cycle.rsp", "-v @cycle.rsp");
+  let args = ~[~"test", format!("@{:s}", path)];
+  let mut ctx = Context::new("test [option] [argument]", args);
+  ctx.set_flags(CtxFlags::ResponseFiles);
+  ctx.add_sopt('v', "Option v");
+  match ctx.validate() {
+    Err(msg) => ctx.print_help(Some(msg.as_slice())),
+    Ok(()) => assert!(false),
+  }
+}
+
+#[test]
+fn test_response_file_missing() {
+  let args = ~[~"test", ~"@/nonexistent/cmdparse.rsp"];
+  let mut ctx = Context::new("test [option] [argument]", args);
+  ctx.set_flags(CtxFlags::ResponseFiles);
+  match ctx.validate() {
+    Err(msg) => ctx.print_help(Some(msg.as_slice())),
+    Ok(()) => assert!(false),
+  }
+}
+
+// Tests for the reuse of a context
+#[test]
+fn test_parse_args_reset() {
+  let args = ~[~"test", ~"-vv", ~"--name=first", ~"command", ~"-f", ~"argument"];
+  let mut ctx = Context::new("test [option] command [command-options] [argument]", args);
+  let verbose = ctx.add_counter(None, Some('v'), None, 3).unwrap();
+  let name = ctx.add_option(Some("name"), None, None, Flags::TakesArg).unwrap();
+  let hidden = ctx.add_option(Some("hidden"), None, None, Flags::Hidden).unwrap();
+  let (cmd_res, f_opt) = ctx.add_cmd_with("command", "description", |cmd| {
+    cmd.add_sopt('f', "Cmd option f")
+  });
+  ctx.validate().map_err(|msg| { ctx.print_help(Some(msg.as_slice())); assert!(false);});
+  assert!(verbose.level() == 2);
+  assert!(cmd_res.check());
+  assert!(f_opt.check());
+
+  ctx.parse_args(~[~"-v", ~"--hidden", ~"other"])
+     .map_err(|msg| { ctx.print_help(Some(msg.as_slice())); assert!(false);});
+  assert!(verbose.level() == 1);
+  assert!(name.value::<~str>() == None);
+  assert!(!name.check());
+  assert!(hidden.check());
+  assert!(!cmd_res.check());
+  assert!(!f_opt.check());
+  assert!(ctx.get_args().as_slice() == &[~"other"]);
+
+  ctx.parse_args(~[]).map_err(|msg| { ctx.print_help(Some(msg.as_slice())); assert!(false);});
+  assert!(!hidden.check());
+  assert!(ctx.get_args().len() == 0);
+}
+
+// Tests for the constructors
+#[test]
+fn test_from_iter() {
+  let mut ctx = Context::from_iter("test [option] [argument]", "-a --long x y".split(' '), false);
+  let a_opt = ctx.add_sopt('a', "Option a");
+  let l_opt = ctx.add_option(Some("long"), None, None, Flags::TakesArg).unwrap();
+  ctx.validate().map_err(|msg| { ctx.print_help(Some(msg.as_slice())); assert!(false);});
+  assert!(a_opt.check());
+  assert!(l_opt.value::<~str>() == Some(~"x"));
+  assert!(ctx.get_args().as_slice() == &[~"y"]);
+}
+
+#[test]
+fn test_from_iter_program_name() {
+  let args: Vec<~str> = (~[~"test", ~"-a"]).move_iter().collect();
+  let mut ctx = Context::from_iter("test [option] [argument]", args.move_iter(), true);
+  let a_opt = ctx.add_sopt('a', "Option a");
+  ctx.validate().map_err(|msg| { ctx.print_help(Some(msg.as_slice())); assert!(false);});
+  assert!(a_opt.check());
+  assert!(ctx.get_args().len() == 0);
+}
+
+#[test]
+fn test_from_env() {
+  let mut ctx = Context::from_env("test [option] [argument]");
+  ctx.add_sopt('a', "Option a");
+}
+
+// Tests for the command line strings
+#[test]
+fn test_split_words() {
+  match split_words(" a  'b c'\\ d \"e \\\"f\\\" \\g\" h\\ i '' \"\"") {
+    Ok(words) => assert!(words.as_slice() == &[~"a", ~"b c d", ~"e \"f\" \\g", ~"h i", ~"", ~""]),
+    Err(_) => assert!(false),
+  }
+  split_words("a 'b").unwrap_err();
+  split_words("a \"b").unwrap_err();
+  split_words("a b\\").unwrap_err();
+}
+
+#[test]
+fn test_quote_words() {
+  let words = ~[~"simple", ~"two words", ~"it's", ~"", ~"$HOME", ~"a\\b\"c"];
+  assert!(quote_word("simple") == ~"simple");
+  assert!(quote_word("it's") == ~"'it'\\''s'");
+  match split_words(join_words(words.as_slice()).as_slice()) {
+    Ok(split) => assert!(split.as_slice() == words.as_slice()),
+    Err(_) => assert!(false),
+  }
+}
+
+#[test]
+fn test_command_line() {
+  let mut ctx = Context::from_command_line("test [option] [argument]",
+                                           "test --name 'a b' c", true).unwrap();
+  let name = ctx.add_option(Some("name"), None, None, Flags::TakesArg).unwrap();
+  ctx.validate().map_err(|msg| { ctx.print_help(Some(msg.as_slice())); assert!(false);});
+  assert!(name.value::<~str>() == Some(~"a b"));
+  assert!(ctx.get_args().as_slice() == &[~"c"]);
+
+  ctx.parse_line("--name=\"d e\"").map_err(|msg| { ctx.print_help(Some(msg.as_slice())); assert!(false);});
+  assert!(name.value::<~str>() == Some(~"d e"));
+  assert!(ctx.get_args().len() == 0);
+  ctx.parse_line("--name 'd").unwrap_err();
+}
+
+// Tests for the arguments which aren't valid UTF-8
+fn bytes_arg(prefix: &str, bytes: &[u8]) -> ~[u8] {
+  let mut arg = prefix.as_bytes().to_owned();
+  arg.push_all(bytes);
+  arg
+}
+
+#[test]
+fn test_non_utf8_args() {
+  let args = ~[bytes_arg("test", [0xff]), bytes_arg("--input=a", [0xfe]),
+               bytes_arg("--tags=b,", [0xfd]), bytes_arg("f", [0xfc])];
+  let mut ctx = Context::from_bytes("test [options] [file]", args.move_iter(), true);
+  let input = ctx.add_option(Some("input"), None, None, Flags::TakesArg).unwrap();
+  let tags = ctx.add_option(Some("tags"), None, None, Flags::TakesArg).unwrap();
+  tags.set_delimiter(',');
+  ctx.validate().map_err(|msg| { ctx.print_help(Some(msg.as_slice())); assert!(false);});
+  assert!(input.raw_value() == Some(bytes_arg("a", [0xfe])));
+  assert!(input.path_value() == Some(Path::new(bytes_arg("a", [0xfe]))));
+  assert!(input.value::<~str>() == Some(~"a\uFFFD"));
+  assert!(tags.raw_values().as_slice() == &[bytes_arg("b", []), bytes_arg("", [0xfd])]);
+  assert!(ctx.get_raw_args().as_slice() == &[bytes_arg("f", [0xfc])]);
+  assert!(ctx.get_args().as_slice() == &[~"f\uFFFD"]);
+
+  // Option names must be valid UTF-8
+  let args = ~[bytes_arg("--", [0xff])];
+  let mut ctx = Context::from_bytes("test [options]", args.move_iter(), false);
+  ctx.validate().unwrap_err();
+}
+
+// Tests for the names built at runtime
+#[test]
+fn test_owned_names() {
+  let mut ctx = Context::new("test [options] [command]",
+                             ~[~"test", ~"--level=3", ~"plugin-run", ~"--fast"]);
+  let names = ~[~"level", ~"plugin-run", ~"fast"];
+  let level = ctx.add_owned_option(Some(names[0].clone()), None,
+                                   Some(format!("Level of the {:s}", "plugin")),
+                                   Flags::TakesArg).unwrap();
+  let (run, fast) = ctx.add_cmd_with(names[1].clone(), ~"Run the plugin", |cmd| {
+    cmd.add_lopt(names[2].clone(), "Run faster")
+  });
+  ctx.add_command_alias(names[1].as_slice(), ~"plugin-go", Flags::Defaults).unwrap();
+  ctx.add_owned_option(Some(~"level"), None, None, Flags::Defaults).unwrap_err();
+  ctx.validate().map_err(|msg| { ctx.print_help(Some(msg.as_slice())); assert!(false);});
+  assert!(level.value::<int>() == Some(3));
+  assert!(run.check());
+  assert!(fast.check());
+
+  ctx.parse_args(~[~"plugin-go"]).map_err(|msg| { ctx.print_help(Some(msg.as_slice())); assert!(false);});
+  assert!(run.check());
+}
+
+#[test]
+fn test_owned_names_variants() {
+  let args = ~[~"test", ~"--jobs=4", ~"-Dk=v", ~"--loud", ~"--loud", ~"--hush", ~"--lvl"];
+  let mut ctx = Context::new("test [options]", args);
+  let jobs: TypedOpt<int> = ctx.add_owned_typed_option(Some(~"jobs"), None, None,
+                                                       Flags::Defaults).unwrap();
+  let defines = ctx.add_owned_map_option(None, Some('D'), None, LastWins).unwrap();
+  let loud = ctx.add_owned_counter(Some(~"loud"), None, None, 3).unwrap();
+  ctx.add_owned_decrement(&loud, Some(~"hush"), None, None).unwrap();
+  ctx.add_owned_alias(&loud, Some(~"lvl"), None, Flags::Defaults).unwrap();
+  ctx.validate().map_err(|msg| { ctx.print_help(Some(msg.as_slice())); assert!(false);});
+  assert!(jobs.get() == Some(4));
+  assert!(defines.value::<~str>() == Some(~"k=v"));
+  assert!(loud.level() == 2);
+  // --hush is printed without the aliases of --loud
+  ctx.print_help(None);
+
+  // The decrement keeps the maximum level of the counter
+  ctx.parse_args(~[~"--hush", ~"--hush", ~"--hush", ~"--hush"]).unwrap();
+  assert!(loud.level() == -3);
+}
+
+// Tests for the owned results
+#[test]
+fn test_parse_result() {
+  let mut ctx = Context::new("test [options] [command]",
+                             ~[~"test", ~"-vv", ~"--out=a", ~"run", ~"--fast", ~"x"]);
+  let verbose = ctx.add_counter(Some("verbose"), Some('v'), None, 3).unwrap();
+  ctx.add_decrement(&verbose, Some("quiet"), Some('q'), None).unwrap();
+  let out = ctx.add_option(Some("out"), None, None, Flags::TakesArg).unwrap();
+  ctx.add_alias(&out, Some("output"), Some('o'), Flags::Defaults).unwrap();
+  ctx.add_cmd_with("run", "Run", |cmd| { cmd.add_lopt("fast", "Run faster"); });
+
+  let result = match ctx.parse() {
+    Ok(result) => result,
+    Err(msg) => { ctx.print_help(Some(msg.as_slice())); fail!() }
+  };
+  let (tx, rx) = channel();
+  spawn(proc() { tx.send(result); });
+  let result = rx.recv();
+
+  assert!(result.options().len() == 3);
+  assert!(result.command() == Some("run"));
+  assert!(result.args() == &[~"x"]);
+  let verbose_result = result.find_id(verbose.id()).unwrap();
+  assert!(verbose_result.name == ~"--verbose");
+  assert!(verbose_result.level == 2);
+  assert!(result.find_option(Some("quiet"), None).unwrap().id == verbose.id());
+  let out_result = result.find_option(None, Some('o')).unwrap();
+  assert!(out_result.long_names.as_slice() == &[~"out", ~"output"]);
+  assert!(out_result.value::<~str>() == Some(~"a"));
+  assert!(result.find_option(Some("fast"), None).is_none());
+  assert!(result.find_command_option("run", Some("fast"), None).unwrap().check());
+}
+
+// Tests for the JSON export
+#[test]
+fn test_json_export() {
+  let mut ctx = Context::new("test [options] [command]",
+                             ~[~"test", ~"--user=me", ~"--password", ~"1234", ~"run", ~"x"]);
+  ctx.add_option(Some("user"), None, None, Flags::TakesArg).unwrap();
+  ctx.add_option(Some("password"), None, None, Flags::TakesArg | Flags::Secret).unwrap();
+  ctx.add_option(Some("unused"), None, None, Flags::Defaults).unwrap();
+  ctx.add_cmd_with("run", "Run", |_| ());
+  let result = match ctx.parse() {
+    Ok(result) => result,
+    Err(msg) => { ctx.print_help(Some(msg.as_slice())); fail!() }
+  };
+  let expected = json::from_str("{\"options\": [\
+    {\"name\": \"--user\", \"count\": 1, \"values\": [\"me\"]},\
+    {\"name\": \"--password\", \"count\": 1, \"values\": [\"<redacted>\"]}],\
+    \"command\": [\"run\"], \"args\": [\"x\"]}").unwrap();
+  assert!(result.to_json() == expected);
+  assert!(!result.to_json().to_str().contains("1234"));
+}
+
+// Tests for the version option
+#[test]
+fn test_version() {
+  let mut ctx = Context::new("test [options] file",
+                             ~[~"test", ~"-V", ~"--unknown", ~"--out"]);
+  let version = ctx.set_version("test 1.0").unwrap();
+  ctx.add_option(Some("out"), None, None, Flags::TakesArg).unwrap();
+  let (tx, rx) = channel();
+  ctx.set_version_writer(~ChanWriter::new(tx));
+  ctx.validate().map_err(|msg| { ctx.print_help(Some(msg.as_slice())); assert!(false);});
+  assert!(version.check());
+  assert!(rx.recv().as_slice() == "test 1.0\n".as_bytes());
+  ctx.set_version("test 1.1").unwrap_err();
+  let mut other = Context::new("test [options]", ~[~"test", ~"--version"]);
+  other.add_sopt('V', "Verbose");
+  other.set_version("test 1.0").unwrap_err();
+  assert!(other.find_option(Some("version"), None).is_none());
+
+  // After the command, -V is left to the command
+  ctx.add_cmd_with("run", "Run", |_| ());
+  ctx.parse_args(~[~"run", ~"-V"]).unwrap_err();
+  assert!(!version.check());
+
+  // A value naming a command isn't taken for the command
+  let (tx, rx) = channel();
+  ctx.set_version_writer(~ChanWriter::new(tx));
+  ctx.add_option(Some("target"), None, None, Flags::TakesArg).unwrap();
+  assert!(ctx.parse_args(~[~"--target", ~"run", ~"-V"]).is_ok());
+  assert!(version.check());
+  assert!(rx.recv().as_slice() == "test 1.0\n".as_bytes());
+
+  // The arguments before -V are checked
+  ctx.parse_args(~[~"--unknown", ~"-V"]).unwrap_err();
+}
+
+// Tests for the automatic help
+fn help_outcome(args: ~[~str]) -> Result<Outcome, ~str> {
+  let mut ctx = Context::new("test [options] [command]", args);
+  ctx.add_option(Some("target"), None, None, Flags::TakesArg).unwrap();
+  ctx.add_cmd_with("run", "Run", |cmd| { cmd.add_lopt("fast", "Run faster"); });
+  ctx.enable_auto_help();
+  ctx.add_cmd_with("build", "Build", |_| ());
+  ctx.add_command_alias("build", "b", Flags::Defaults).unwrap();
+  ctx.set_flags(CtxFlags::ResponseFiles);
+  ctx.validate_outcome()
+}
+
+#[test]
+fn test_auto_help() {
+  assert!(help_outcome(~[~"test", ~"-h", ~"--bogus"]) == Ok(HelpRequested(None)));
+  assert!(help_outcome(~[~"test", ~"--bogus", ~"-h"]).is_err());
+  assert!(help_outcome(~[~"test", ~"run", ~"--help"]) == Ok(HelpRequested(Some(~"run"))));
+  assert!(help_outcome(~[~"test", ~"b", ~"-h"]) == Ok(HelpRequested(Some(~"build"))));
+  assert!(help_outcome(~[~"test", ~"help", ~"run"]) == Ok(HelpRequested(Some(~"run"))));
+  assert!(help_outcome(~[~"test", ~"help"]) == Ok(HelpRequested(None)));
+  assert!(help_outcome(~[~"test", ~"help", ~"bogus"]).is_err());
+  assert!(help_outcome(~[~"test", ~"run", ~"--fast"]) == Ok(Proceed));
+
+  // The values of the options aren't taken for the help command or a command
+  assert!(help_outcome(~[~"test", ~"--target", ~"help"]) == Ok(Proceed));
+  assert!(help_outcome(~[~"test", ~"--target", ~"build", ~"-h"]) == Ok(HelpRequested(None)));
+
+  // validate() reports the help as an error
+  let mut ctx = Context::new("test [options]", ~[~"test", ~"--help"]);
+  ctx.enable_auto_help();
+  ctx.validate().unwrap_err();
+
+  // The names already used are kept
+  let mut ctx = Context::new("test [options]", ~[~"test", ~"-h"]);
+  let host = ctx.add_option(None, Some('h'), Some("Host"), Flags::Defaults).unwrap();
+  ctx.enable_auto_help();
+  assert!(ctx.validate_outcome() == Ok(Proceed));
+  assert!(host.check());
+  assert!(ctx.find_option(Some("help"), None).is_some());
+}
+
+// Tests for the help of a command
+#[test]
+fn test_command_help() {
+  let mut ctx = Context::new("test [options] [command]", ~[~"test"]);
+  ctx.add_opt("verbose", 'v', "Print more");
+  ctx.add_cmd_with("deploy", "Deploy the application", |cmd| {
+    cmd.add_option(Some("target"), Some('t'), Some("Where to deploy"), Flags::TakesArg).unwrap();
+  });
+  ctx.add_command_alias("deploy", "d", Flags::Defaults).unwrap();
+  ctx.print_command_help("deploy", None).unwrap();
+  ctx.print_command_help("d", Some("Missing target")).unwrap();
+  ctx.print_command_help("bogus", None).unwrap_err();
+}